@@ -0,0 +1,24 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+CREATE TABLE pending_uploads
+(
+  id                  INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id             INTEGER                            NOT NULL,
+  uuid                TEXT UNIQUE                        NOT NULL,
+  salt                TEXT                               NOT NULL,
+  filename_iv         TEXT                               NOT NULL,
+  data_iv             TEXT                               NOT NULL,
+  encrypted_filename  TEXT                               NOT NULL,
+  e2ee_passkey_id     INTEGER,
+  downloads_remaining INTEGER,
+  expires_at          DATETIME,
+  upload_length       INTEGER                            NOT NULL,
+  created_at          DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  updated_at          DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+  FOREIGN KEY (e2ee_passkey_id) REFERENCES passkeys (id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE files ADD COLUMN format_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE pending_uploads ADD COLUMN format_version INTEGER NOT NULL DEFAULT 1;
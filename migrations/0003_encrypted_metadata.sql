@@ -0,0 +1,11 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+-- Existing rows keep their format_version, for which the metadata is just the filename
+ALTER TABLE files RENAME COLUMN filename_iv TO metadata_iv;
+ALTER TABLE files RENAME COLUMN encrypted_filename TO encrypted_metadata;
+ALTER TABLE pending_uploads RENAME COLUMN filename_iv TO metadata_iv;
+ALTER TABLE pending_uploads RENAME COLUMN encrypted_filename TO encrypted_metadata;
@@ -0,0 +1,9 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE users ADD COLUMN max_bytes INTEGER;
+ALTER TABLE users ADD COLUMN max_files INTEGER;
+ALTER TABLE files ADD COLUMN size INTEGER;
@@ -0,0 +1,9 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE users ADD COLUMN default_expires_in INTEGER;
+ALTER TABLE users ADD COLUMN max_expires_in INTEGER;
+ALTER TABLE users ADD COLUMN require_max_downloads INTEGER;
@@ -0,0 +1,7 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE files ADD COLUMN trash_expires_at DATETIME;
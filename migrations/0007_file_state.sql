@@ -0,0 +1,9 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE files ADD COLUMN state TEXT DEFAULT 'live' NOT NULL CHECK (state IN ('pending', 'live', 'tombstoned'));
+-- Previously, deleted files were marked by clearing `salt`
+UPDATE files SET state = 'tombstoned' WHERE salt IS NULL;
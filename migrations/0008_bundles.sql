@@ -0,0 +1,18 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+CREATE TABLE bundles
+(
+  id                  INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id             INTEGER                            NOT NULL,
+  uuid                TEXT UNIQUE                        NOT NULL,
+  downloads_remaining INTEGER,
+  created_at          DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  expires_at          DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+ALTER TABLE files ADD COLUMN bundle_id INTEGER REFERENCES bundles (id) ON DELETE SET NULL;
@@ -0,0 +1,8 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE files ADD COLUMN kind TEXT DEFAULT 'file' NOT NULL CHECK (kind IN ('file', 'clip'));
+ALTER TABLE files ADD COLUMN inline_data BLOB;
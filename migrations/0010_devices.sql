@@ -0,0 +1,19 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+CREATE TABLE devices
+(
+  id           INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id      INTEGER                            NOT NULL,
+  uuid         TEXT UNIQUE                        NOT NULL,
+  name         TEXT                               NOT NULL,
+  created_at   DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+ALTER TABLE files ADD COLUMN uploaded_by_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL;
+ALTER TABLE files ADD COLUMN target_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL;
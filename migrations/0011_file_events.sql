@@ -0,0 +1,21 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+CREATE TABLE file_events
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id    INTEGER                            NOT NULL,
+  file_uuid  TEXT                               NOT NULL,
+  action     TEXT                               NOT NULL CHECK (action IN ('upload', 'download', 'delete', 'expire')),
+  created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  passkey_id INTEGER REFERENCES passkeys (id) ON DELETE SET NULL,
+  device_id  INTEGER REFERENCES devices (id) ON DELETE SET NULL,
+  ip         TEXT,
+  user_agent TEXT,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX file_events_file_uuid ON file_events (file_uuid);
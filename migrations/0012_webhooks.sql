@@ -0,0 +1,37 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+CREATE TABLE webhooks
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id    INTEGER                            NOT NULL,
+  uuid       TEXT UNIQUE                        NOT NULL,
+  url        TEXT                               NOT NULL,
+  -- JSON array of file actions, such as `["upload", "download"]`
+  events     TEXT                               NOT NULL,
+  secret     TEXT                               NOT NULL,
+  created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE webhook_deliveries
+(
+  id              INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  webhook_id      INTEGER                            NOT NULL,
+  uuid            TEXT UNIQUE                        NOT NULL,
+  event           TEXT                               NOT NULL CHECK (event IN ('upload', 'download', 'delete', 'expire', 'ping')),
+  payload         TEXT                               NOT NULL,
+  state           TEXT DEFAULT 'pending'             NOT NULL CHECK (state IN ('pending', 'delivered', 'failed')),
+  attempts        INTEGER DEFAULT 0                  NOT NULL,
+  next_attempt_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  last_status     INTEGER,
+  last_error      TEXT,
+  created_at      DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  updated_at      DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (webhook_id) REFERENCES webhooks (id) ON DELETE CASCADE
+);
+
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (state, next_attempt_at);
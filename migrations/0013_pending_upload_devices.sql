@@ -0,0 +1,8 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+ALTER TABLE pending_uploads ADD COLUMN uploaded_by_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL;
+ALTER TABLE pending_uploads ADD COLUMN target_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL;
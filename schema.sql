@@ -6,10 +6,15 @@
 
 CREATE TABLE users
 (
-  id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-  username   TEXT UNIQUE                       NOT NULL,
-  uuid       TEXT UNIQUE                       NOT NULL,
-  created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+  id                    INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  username              TEXT UNIQUE                       NOT NULL,
+  uuid                  TEXT UNIQUE                       NOT NULL,
+  created_at            DATETIME DEFAULT CURRENT_TIMESTAMP,
+  max_bytes             INTEGER,
+  max_files             INTEGER,
+  default_expires_in    INTEGER,
+  max_expires_in        INTEGER,
+  require_max_downloads INTEGER
 );
 
 CREATE TABLE registration_tokens
@@ -32,19 +37,122 @@ CREATE TABLE passkeys
   FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
 );
 
-CREATE TABLE files
+CREATE TABLE devices
+(
+  id           INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id      INTEGER                            NOT NULL,
+  uuid         TEXT UNIQUE                        NOT NULL,
+  name         TEXT                               NOT NULL,
+  created_at   DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE bundles
 (
   id                  INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
   user_id             INTEGER                            NOT NULL,
   uuid                TEXT UNIQUE                        NOT NULL,
-  salt                TEXT,
-  filename_iv         TEXT                               NOT NULL,
-  data_iv             TEXT                               NOT NULL,
-  encrypted_filename  TEXT                               NOT NULL,
-  e2ee_passkey_id     INTEGER,
   downloads_remaining INTEGER,
   created_at          DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
   expires_at          DATETIME,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE files
+(
+  id                    INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id               INTEGER                            NOT NULL,
+  uuid                  TEXT UNIQUE                        NOT NULL,
+  salt                  TEXT,
+  metadata_iv           TEXT                               NOT NULL,
+  data_iv               TEXT                               NOT NULL,
+  encrypted_metadata    TEXT                               NOT NULL,
+  e2ee_passkey_id       INTEGER,
+  downloads_remaining   INTEGER,
+  created_at            DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  expires_at            DATETIME,
+  format_version        INTEGER DEFAULT 1                  NOT NULL,
+  size                  INTEGER,
+  trash_expires_at      DATETIME,
+  state                 TEXT DEFAULT 'live'                NOT NULL CHECK (state IN ('pending', 'live', 'tombstoned')),
+  bundle_id             INTEGER REFERENCES bundles (id) ON DELETE SET NULL,
+  kind                  TEXT DEFAULT 'file'                NOT NULL CHECK (kind IN ('file', 'clip')),
+  inline_data           BLOB,
+  uploaded_by_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL,
+  target_device_id      INTEGER REFERENCES devices (id) ON DELETE SET NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+  FOREIGN KEY (e2ee_passkey_id) REFERENCES passkeys (id) ON DELETE CASCADE
+);
+
+CREATE TABLE pending_uploads
+(
+  id                    INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id               INTEGER                            NOT NULL,
+  uuid                  TEXT UNIQUE                        NOT NULL,
+  salt                  TEXT                               NOT NULL,
+  metadata_iv           TEXT                               NOT NULL,
+  data_iv               TEXT                               NOT NULL,
+  encrypted_metadata    TEXT                               NOT NULL,
+  e2ee_passkey_id       INTEGER,
+  downloads_remaining   INTEGER,
+  expires_at            DATETIME,
+  upload_length         INTEGER                            NOT NULL,
+  created_at            DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  updated_at            DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  format_version        INTEGER DEFAULT 1                  NOT NULL,
+  uploaded_by_device_id INTEGER REFERENCES devices (id) ON DELETE SET NULL,
+  target_device_id      INTEGER REFERENCES devices (id) ON DELETE SET NULL,
   FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
   FOREIGN KEY (e2ee_passkey_id) REFERENCES passkeys (id) ON DELETE CASCADE
 );
+
+CREATE TABLE file_events
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id    INTEGER                            NOT NULL,
+  file_uuid  TEXT                               NOT NULL,
+  action     TEXT                               NOT NULL CHECK (action IN ('upload', 'download', 'delete', 'expire')),
+  created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  passkey_id INTEGER REFERENCES passkeys (id) ON DELETE SET NULL,
+  device_id  INTEGER REFERENCES devices (id) ON DELETE SET NULL,
+  ip         TEXT,
+  user_agent TEXT,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX file_events_file_uuid ON file_events (file_uuid);
+
+CREATE TABLE webhooks
+(
+  id         INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  user_id    INTEGER                            NOT NULL,
+  uuid       TEXT UNIQUE                        NOT NULL,
+  url        TEXT                               NOT NULL,
+  -- JSON array of file actions, such as `["upload", "download"]`
+  events     TEXT                               NOT NULL,
+  secret     TEXT                               NOT NULL,
+  created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE webhook_deliveries
+(
+  id              INTEGER PRIMARY KEY AUTOINCREMENT  NOT NULL,
+  webhook_id      INTEGER                            NOT NULL,
+  uuid            TEXT UNIQUE                        NOT NULL,
+  event           TEXT                               NOT NULL CHECK (event IN ('upload', 'download', 'delete', 'expire', 'ping')),
+  payload         TEXT                               NOT NULL,
+  state           TEXT DEFAULT 'pending'             NOT NULL CHECK (state IN ('pending', 'delivered', 'failed')),
+  attempts        INTEGER DEFAULT 0                  NOT NULL,
+  next_attempt_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  last_status     INTEGER,
+  last_error      TEXT,
+  created_at      DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  updated_at      DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  FOREIGN KEY (webhook_id) REFERENCES webhooks (id) ON DELETE CASCADE
+);
+
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (state, next_attempt_at);
+
+PRAGMA user_version = 13;
@@ -6,22 +6,80 @@
 
 use rocket::http::Status;
 use rocket::response::Responder;
+use rocket::serde::json::Json;
 use rocket::{Request, response};
+use serde::Serialize;
+use ts_rs::TS;
 use webauthn_rs::prelude::WebauthnError;
 
+/// Response body for `ApiError::InvalidFieldError`
+#[derive(Debug, Serialize, TS)]
+#[ts(export_to = "api/InvalidField.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct InvalidField {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+/// Response body for `ApiError::QuotaExceeded`
+#[derive(Debug, Serialize, TS)]
+#[ts(export_to = "api/QuotaExceeded.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct QuotaExceeded {
+    /// Always `quota_exceeded`, to tell this apart from `InsufficientStorage`
+    #[ts(type = "'quota_exceeded'")]
+    pub code: &'static str,
+    #[ts(type = "'bytes' | 'files'")]
+    pub quota: &'static str,
+    #[ts(type = "number")]
+    pub limit: i64,
+    #[ts(type = "number")]
+    pub used: i64,
+    /// How much the rejected request would have added
+    #[ts(type = "number")]
+    pub requested: i64,
+}
+
+/// Response body for `ApiError::InsufficientStorageError`
+#[derive(Debug, Serialize, TS)]
+#[ts(export_to = "api/InsufficientStorage.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct InsufficientStorage {
+    #[ts(type = "'insufficient_storage'")]
+    pub code: &'static str,
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ApiError {
     NotFoundError(),
     InvalidSessionError(),
     BadRequestError(String),
+    InvalidFieldError(InvalidField),
+    ConflictError(String),
+    QuotaExceeded(QuotaExceeded),
+    /// The server is low on disk space; see `DiskSpace`
+    InsufficientStorageError(),
     DatabaseError(sqlx::Error),
     WebauthnError(WebauthnError),
     IOError(std::io::Error),
 }
 
+impl ApiError {
+    pub fn invalid_field<T: Into<String>>(field: &'static str, reason: T) -> Self {
+        ApiError::InvalidFieldError(InvalidField {
+            field,
+            reason: reason.into(),
+        })
+    }
+}
+
 impl From<std::io::Error> for ApiError {
     fn from(e: std::io::Error) -> Self {
-        ApiError::IOError(e)
+        match e.kind() {
+            std::io::ErrorKind::StorageFull => ApiError::InsufficientStorageError(),
+            _ => ApiError::IOError(e),
+        }
     }
 }
 
@@ -47,22 +105,14 @@ impl<'r> Responder<'r, 'static> for ApiError {
             ApiError::InvalidSessionError() => Status::Unauthorized.respond_to(r),
             ApiError::IOError(e) => {
                 if cfg!(debug_assertions) {
-                    (
-                        Status::InternalServerError,
-                        format!("IO error: {}", e.to_string()),
-                    )
-                        .respond_to(r)
+                    (Status::InternalServerError, format!("IO error: {}", e)).respond_to(r)
                 } else {
                     Status::InternalServerError.respond_to(r)
                 }
             }
             ApiError::DatabaseError(e) => {
                 if cfg!(debug_assertions) {
-                    (
-                        Status::InternalServerError,
-                        format!("SQL error: {}", e.to_string()),
-                    )
-                        .respond_to(r)
+                    (Status::InternalServerError, format!("SQL error: {}", e)).respond_to(r)
                 } else {
                     Status::InternalServerError.respond_to(r)
                 }
@@ -71,13 +121,39 @@ impl<'r> Responder<'r, 'static> for ApiError {
                 if cfg!(debug_assertions) {
                     (
                         Status::InternalServerError,
-                        format!("Webauthn error: {}", e.to_string()),
+                        format!("Webauthn error: {}", e),
                     )
                         .respond_to(r)
                 } else {
                     Status::InternalServerError.respond_to(r)
                 }
             }
+            // Not a secret, and useful for non-browser clients even in release builds
+            ApiError::InvalidFieldError(e) => (Status::BadRequest, Json(e)).respond_to(r),
+            // 413 if the request could never succeed, 507 if deleting files would help
+            ApiError::QuotaExceeded(e) => {
+                let status = if e.requested > e.limit {
+                    Status::PayloadTooLarge
+                } else {
+                    Status::InsufficientStorage
+                };
+                (status, Json(e)).respond_to(r)
+            }
+            // The same status as `QuotaExceeded`, so clients need `code` to tell them apart
+            ApiError::InsufficientStorageError() => (
+                Status::InsufficientStorage,
+                Json(InsufficientStorage {
+                    code: "insufficient_storage",
+                }),
+            )
+                .respond_to(r),
+            ApiError::ConflictError(s) => {
+                if cfg!(debug_assertions) {
+                    (Status::Conflict, format!("Conflict: {}", s)).respond_to(r)
+                } else {
+                    Status::Conflict.respond_to(r)
+                }
+            }
             ApiError::BadRequestError(s) => {
                 if cfg!(debug_assertions) {
                     (Status::BadRequest, format!("Bad request: {}", s)).respond_to(r)
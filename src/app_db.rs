@@ -9,3 +9,14 @@ use rocket_db_pools::sqlx;
 #[derive(rocket_db_pools::Database, Clone)]
 #[database("app_db")]
 pub struct AppDb(sqlx::SqlitePool);
+
+/// An empty in-memory database with the current schema.
+#[cfg(test)]
+pub async fn test_db() -> sqlx::SqliteConnection {
+    use sqlx::{Connection, Executor};
+    let mut conn = sqlx::SqliteConnection::connect("sqlite::memory:")
+        .await
+        .unwrap();
+    conn.execute(include_str!("../schema.sql")).await.unwrap();
+    conn
+}
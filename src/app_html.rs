@@ -36,8 +36,8 @@ impl AppHtml {
 
     fn get_vite_footer(vite_config: &ViteConfig) -> String {
         match vite_config {
-            ViteConfig::Dev { origin } => Self::get_vite_dev_footer(&origin),
-            ViteConfig::Release { root } => Self::get_vite_release_footer(&root),
+            ViteConfig::Dev { origin } => Self::get_vite_dev_footer(origin),
+            ViteConfig::Release { root } => Self::get_vite_release_footer(root),
         }
     }
 
@@ -74,14 +74,14 @@ impl AppHtml {
             None => vec![],
         };
 
-        return format!(
+        format!(
             r#"
             {}
             <script type="module" src="/{}"></script>
             "#,
             css_link_tags.join("\n"),
             app.file,
-        );
+        )
     }
 }
 
@@ -0,0 +1,169 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use super::{BlobReader, BlobStore, Listing};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncSeekExt;
+use uuid::Uuid;
+
+/// Each shard level uses 2 characters of the UUID; this keeps them all before the first `-`.
+pub const MAX_SHARD_DEPTH: usize = 4;
+
+/// Blobs stored as `<root>/xx/yy/<uuid>` for a shard depth of 2, where `xxyy` are the first 4
+/// characters of the UUID.
+///
+/// Entries in `root` starting with `.` are left alone, e.g. the upload staging directory and
+/// `.quarantine`.
+pub struct LocalBlobStore {
+    root: PathBuf,
+    shard_depth: usize,
+}
+
+/// Rename, falling back to a copy if `to` is on a different filesystem.
+pub(super) async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            tokio::fs::copy(from, to).await?;
+            tokio::fs::remove_file(from).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl LocalBlobStore {
+    pub fn new<P: Into<PathBuf>>(root: P, shard_depth: usize) -> Self {
+        Self {
+            root: root.into(),
+            shard_depth: shard_depth.min(MAX_SHARD_DEPTH),
+        }
+    }
+
+    fn path(&self, uuid: Uuid) -> PathBuf {
+        let uuid_str = uuid.to_string();
+        let mut path = self.root.clone();
+        for level in 0..self.shard_depth {
+            path.push(&uuid_str[level * 2..(level + 1) * 2]);
+        }
+        path.join(uuid_str)
+    }
+
+    /// Remove now-empty shard directories; this fails harmlessly if they're not empty.
+    async fn remove_shard_directories(&self, path: &Path) {
+        for dir in path.ancestors().skip(1).take(self.shard_depth) {
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Move a blob into another store, if it's present in this one.
+    pub async fn move_to(&self, uuid: Uuid, target: &dyn BlobStore) -> std::io::Result<bool> {
+        let path = self.path(uuid);
+        if !tokio::fs::try_exists(&path).await? {
+            return Ok(false);
+        }
+        target.put(uuid, &path).await?;
+        self.remove_shard_directories(&path).await;
+        Ok(true)
+    }
+}
+
+fn visit_directory(dir: &Path, visitor: &mut dyn FnMut(&Path)) -> std::io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if path.is_dir() {
+            visit_directory(&path, visitor)?;
+        }
+
+        visitor(&path);
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, uuid: Uuid, source: &Path) -> std::io::Result<()> {
+        let path = self.path(uuid);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        move_file(source, &path).await
+    }
+
+    async fn size(&self, uuid: Uuid) -> std::io::Result<u64> {
+        Ok(tokio::fs::metadata(self.path(uuid)).await?.len())
+    }
+
+    async fn get(&self, uuid: Uuid, offset: u64) -> std::io::Result<BlobReader> {
+        let mut file = tokio::fs::File::open(self.path(uuid)).await?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Box::pin(file))
+    }
+
+    async fn delete(&self, uuid: Uuid) -> std::io::Result<()> {
+        let path = self.path(uuid);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        self.remove_shard_directories(&path).await;
+        Ok(())
+    }
+
+    async fn list(&self) -> std::io::Result<Listing> {
+        let mut paths: Vec<PathBuf> = vec![];
+        visit_directory(&self.root, &mut |path| {
+            if path.is_file() {
+                paths.push(path.to_path_buf())
+            }
+        })?;
+        let mut listing = Listing::default();
+        for path in paths {
+            // Files in the wrong shard directory can't be found by `path()`
+            match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| Uuid::parse_str(name).ok())
+            {
+                Some(uuid) if path == self.path(uuid) => listing.blobs.push(uuid),
+                _ => listing.stray.push(path.to_string_lossy().into_owned()),
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Moves `<root>/<path>` to `<root>/.quarantine/<path>`.
+    async fn quarantine(&self, stray: &str) -> std::io::Result<()> {
+        let from = Path::new(stray);
+        let Ok(relative) = from.strip_prefix(&self.root) else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Not in this store: {}", stray),
+            ));
+        };
+        let to = self.root.join(".quarantine").join(relative);
+        tokio::fs::create_dir_all(to.parent().unwrap()).await?;
+        move_file(from, &to).await?;
+        for dir in from.ancestors().skip(1) {
+            if dir == self.root || tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
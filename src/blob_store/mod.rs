@@ -0,0 +1,254 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Where encrypted file contents are kept.
+//!
+//! The database is the source of truth for which files exist; a blob store just maps UUIDs
+//! to bytes. Uploads are always assembled and validated on local disk first, then handed
+//! over with `put()`, unless they're small enough to be stored inline (see `InlineThreshold`).
+
+mod local;
+mod s3;
+
+pub use local::{LocalBlobStore, MAX_SHARD_DEPTH};
+pub use s3::S3BlobStore;
+
+use crate::disk_space::DiskSpace;
+use rocket::data::ByteUnit;
+use rocket::figment::Figment;
+use rocket::figment::providers::Serialized;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// The contents of a blob store, from `BlobStore::list()`.
+#[derive(Default)]
+pub struct Listing {
+    pub blobs: Vec<Uuid>,
+    /// Files or objects that can't be blobs, e.g. because their names aren't UUIDs
+    pub stray: Vec<String>,
+}
+
+#[rocket::async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Take ownership of the local file at `source`, storing it as the blob for `uuid`.
+    async fn put(&self, uuid: Uuid, source: &Path) -> std::io::Result<()>;
+    /// The size of a blob, in bytes.
+    async fn size(&self, uuid: Uuid) -> std::io::Result<u64>;
+    /// Stream a blob, starting `offset` bytes in.
+    async fn get(&self, uuid: Uuid, offset: u64) -> std::io::Result<BlobReader>;
+    /// Remove a blob; removing a blob that doesn't exist is not an error.
+    async fn delete(&self, uuid: Uuid) -> std::io::Result<()>;
+    /// Every blob in the store, whether or not it's referenced by the database.
+    async fn list(&self) -> std::io::Result<Listing>;
+    /// Move a stray entry from `list()` aside, so that it isn't listed again.
+    async fn quarantine(&self, stray: &str) -> std::io::Result<()>;
+}
+
+/// Local directory for resumable uploads that haven't been completed yet.
+///
+/// This is `<storage.root>/.staging`, whichever backend is in use.
+#[derive(Clone)]
+pub struct StagingArea {
+    root: PathBuf,
+}
+
+impl StagingArea {
+    pub fn new<P: Into<PathBuf>>(storage_root: P) -> Self {
+        Self {
+            root: storage_root.into().join(".staging"),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn path(&self, uuid: Uuid) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(&self.root)?;
+        Ok(self.root.join(uuid.to_string()))
+    }
+
+    /// Incomplete uploads in the staging area, whether or not they're in the database.
+    pub fn list(&self) -> std::io::Result<Listing> {
+        let mut listing = Listing::default();
+        if !self.root.is_dir() {
+            return Ok(listing);
+        }
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            match Uuid::parse_str(&name) {
+                Ok(uuid) if entry.file_type()?.is_file() => listing.blobs.push(uuid),
+                _ => listing
+                    .stray
+                    .push(entry.path().to_string_lossy().into_owned()),
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Moves a stray entry from `list()` into `<root>/.quarantine/`.
+    pub fn quarantine(&self, stray: &str) -> std::io::Result<()> {
+        let from = Path::new(stray);
+        let quarantine = self.root.join(".quarantine");
+        std::fs::create_dir_all(&quarantine)?;
+        std::fs::rename(from, quarantine.join(from.file_name().unwrap_or_default()))
+    }
+}
+
+/// Uploads of up to this many bytes are kept in `files.inline_data` rather than the blob store,
+/// so that small files don't each need their own blob.
+#[derive(Clone, Copy)]
+pub struct InlineThreshold(pub u64);
+
+/// The `storage` section of `Rocket.toml`
+#[derive(Deserialize)]
+pub struct StorageConfig {
+    /// Local blobs and incomplete uploads are kept here
+    pub root: PathBuf,
+    /// Directory levels for local blobs; each level is named after 2 characters of the UUID
+    pub shard_depth: usize,
+    /// Uploads are refused if they would leave less than this free on local disk
+    pub min_free_space: ByteUnit,
+    /// Ciphertexts up to this size are stored in the database instead of the blob store
+    pub inline_threshold: ByteUnit,
+    #[serde(flatten)]
+    pub backend: BackendConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum BackendConfig {
+    Local,
+    /// Any S3-compatible service, such as AWS or MinIO.
+    ///
+    /// Credentials and region default to the usual `AWS_*` environment variables.
+    S3 {
+        bucket: String,
+        endpoint: Option<String>,
+        region: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+        /// Required for `http://` endpoints
+        #[serde(default)]
+        allow_http: bool,
+        /// Prepended to every key, e.g. `tempfiles/`
+        prefix: Option<String>,
+    },
+}
+
+impl StorageConfig {
+    /// Missing settings default to local storage in `uploads/`, with a shard depth of 2,
+    /// keeping 1GiB free, and storing ciphertexts of up to 16KiB in the database.
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Self> {
+        let config: Self = figment
+            .clone()
+            .join(Serialized::default(
+                "storage",
+                serde_json::json!({
+                    "type": "Local",
+                    "root": "uploads",
+                    "shard_depth": 2,
+                    "min_free_space": "1GiB",
+                    "inline_threshold": "16KiB",
+                }),
+            ))
+            .extract_inner("storage")?;
+        if config.shard_depth > MAX_SHARD_DEPTH {
+            anyhow::bail!("storage.shard_depth must be at most {}", MAX_SHARD_DEPTH);
+        }
+        Ok(config)
+    }
+
+    pub fn staging(&self) -> StagingArea {
+        StagingArea::new(&self.root)
+    }
+
+    pub fn inline_threshold(&self) -> InlineThreshold {
+        InlineThreshold(self.inline_threshold.as_u64())
+    }
+
+    /// Local blobs, incomplete uploads, and multipart uploads all need space.
+    pub fn disk_space(&self) -> DiskSpace {
+        DiskSpace::new(
+            vec![self.root.clone(), std::env::temp_dir()],
+            self.min_free_space.as_u64(),
+        )
+    }
+
+    pub fn open(&self) -> anyhow::Result<Arc<dyn BlobStore>> {
+        Ok(match &self.backend {
+            BackendConfig::Local => Arc::new(LocalBlobStore::new(&self.root, self.shard_depth)),
+            BackendConfig::S3 {
+                bucket,
+                endpoint,
+                region,
+                access_key_id,
+                secret_access_key,
+                allow_http,
+                prefix,
+            } => Arc::new(S3BlobStore::new(
+                bucket,
+                endpoint.as_deref(),
+                region.as_deref(),
+                access_key_id.as_deref(),
+                secret_access_key.as_deref(),
+                *allow_http,
+                prefix.as_deref(),
+            )?),
+        })
+    }
+}
+
+/// Move every blob in a local layout into `target`, e.g. after changing `storage.root` or
+/// `storage.shard_depth`, or when switching to S3.
+///
+/// Incomplete uploads are moved to `staging`, so they can still be resumed.
+pub async fn migrate(
+    source_root: &Path,
+    source_shard_depth: usize,
+    target: &dyn BlobStore,
+    staging: &StagingArea,
+) -> anyhow::Result<()> {
+    let source = LocalBlobStore::new(source_root, source_shard_depth);
+    let listing = source.list().await?;
+    let mut moved = 0;
+    for uuid in listing.blobs {
+        if source.move_to(uuid, target).await? {
+            moved += 1;
+        }
+    }
+    println!("Moved {} files", moved);
+    for stray in listing.stray {
+        println!("Skipped unexpected file: {}", stray);
+    }
+
+    let source_staging = StagingArea::new(source_root);
+    if source_staging.root() != staging.root() && source_staging.root().is_dir() {
+        for entry in std::fs::read_dir(source_staging.root())? {
+            let entry = entry?;
+            let Some(uuid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            local::move_file(&entry.path(), &staging.path(uuid)?).await?;
+            println!("Moved incomplete upload: {}", uuid);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use super::{BlobReader, BlobStore, Listing};
+use futures::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, GetRange, ObjectStore};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+/// Blobs stored as `<prefix><uuid>` in an S3-compatible bucket.
+pub struct S3BlobStore {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+}
+
+fn to_io_error(e: object_store::Error) -> std::io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => std::io::Error::new(ErrorKind::NotFound, e),
+        _ => std::io::Error::other(e),
+    }
+}
+
+impl S3BlobStore {
+    pub fn new(
+        bucket: &str,
+        endpoint: Option<&str>,
+        region: Option<&str>,
+        access_key_id: Option<&str>,
+        secret_access_key: Option<&str>,
+        allow_http: bool,
+        prefix: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_allow_http(allow_http);
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(region) = region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        Ok(Self {
+            store: Arc::new(builder.build()?),
+            prefix: ObjectPath::parse(prefix.unwrap_or(""))?,
+        })
+    }
+
+    fn location(&self, uuid: Uuid) -> ObjectPath {
+        self.prefix.child(uuid.to_string())
+    }
+
+    fn quarantine_prefix(&self) -> ObjectPath {
+        self.prefix.child(".quarantine")
+    }
+}
+
+#[rocket::async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, uuid: Uuid, source: &Path) -> std::io::Result<()> {
+        let mut file = tokio::fs::File::open(source).await?;
+        let mut writer = BufWriter::new(self.store.clone(), self.location(uuid));
+        if let Err(e) = tokio::io::copy(&mut file, &mut writer).await {
+            writer.abort().await.map_err(to_io_error)?;
+            return Err(e);
+        }
+        writer.shutdown().await?;
+        drop(file);
+        tokio::fs::remove_file(source).await
+    }
+
+    async fn size(&self, uuid: Uuid) -> std::io::Result<u64> {
+        let meta = self
+            .store
+            .head(&self.location(uuid))
+            .await
+            .map_err(to_io_error)?;
+        Ok(meta.size)
+    }
+
+    async fn get(&self, uuid: Uuid, offset: u64) -> std::io::Result<BlobReader> {
+        // Ranges must be non-empty, so a zero-byte blob can only be fetched without one
+        let options = GetOptions {
+            range: (offset > 0).then_some(GetRange::Offset(offset)),
+            ..Default::default()
+        };
+        let result = self
+            .store
+            .get_opts(&self.location(uuid), options)
+            .await
+            .map_err(to_io_error)?;
+        Ok(Box::pin(StreamReader::new(
+            result.into_stream().map_err(to_io_error),
+        )))
+    }
+
+    async fn delete(&self, uuid: Uuid) -> std::io::Result<()> {
+        match self.store.delete(&self.location(uuid)).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(to_io_error(e)),
+        }
+    }
+
+    async fn list(&self) -> std::io::Result<Listing> {
+        let objects: Vec<_> = self
+            .store
+            .list(Some(&self.prefix))
+            .try_collect()
+            .await
+            .map_err(to_io_error)?;
+        let mut listing = Listing::default();
+        for object in objects {
+            if object.location.prefix_matches(&self.quarantine_prefix()) {
+                continue;
+            }
+            match object
+                .location
+                .filename()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            {
+                Some(uuid) if object.location == self.location(uuid) => listing.blobs.push(uuid),
+                _ => listing.stray.push(object.location.to_string()),
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Moves `<prefix>/<name>` to `<prefix>/.quarantine/<name>`.
+    async fn quarantine(&self, stray: &str) -> std::io::Result<()> {
+        let quarantine = self.quarantine_prefix();
+        let from = ObjectPath::from(stray);
+        let Some(name) = from.prefix_match(&self.prefix) else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Not in this store: {}", stray),
+            ));
+        };
+        let to = ObjectPath::from_iter(quarantine.parts().chain(name));
+        self.store.rename(&from, &to).await.map_err(to_io_error)
+    }
+}
+
+/// These need an S3-compatible server, such as MinIO; see the README for how to start one, then
+/// run `cargo test -- --ignored` with `TEMPFILES_TEST_S3_ENDPOINT` and `TEMPFILES_TEST_S3_BUCKET`
+/// set. Credentials come from the usual `AWS_*` environment variables.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::PutPayload;
+    use tokio::io::AsyncReadExt;
+
+    fn store() -> S3BlobStore {
+        let endpoint = std::env::var("TEMPFILES_TEST_S3_ENDPOINT")
+            .expect("TEMPFILES_TEST_S3_ENDPOINT must be set");
+        let bucket = std::env::var("TEMPFILES_TEST_S3_BUCKET")
+            .expect("TEMPFILES_TEST_S3_BUCKET must be set");
+        // A fresh prefix for each test, so that they don't see each other's blobs
+        let prefix = format!("test-{}", Uuid::new_v4());
+        S3BlobStore::new(
+            &bucket,
+            Some(&endpoint),
+            Some("us-east-1"),
+            None,
+            None,
+            endpoint.starts_with("http://"),
+            Some(&prefix),
+        )
+        .unwrap()
+    }
+
+    async fn put_bytes(store: &S3BlobStore, uuid: Uuid, data: &[u8]) {
+        let source = std::env::temp_dir().join(format!("tempfiles-test-{}", uuid));
+        tokio::fs::write(&source, data).await.unwrap();
+        store.put(uuid, &source).await.unwrap();
+        assert!(
+            !source.exists(),
+            "put() should take ownership of the source"
+        );
+    }
+
+    async fn read(store: &S3BlobStore, uuid: Uuid, offset: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        store
+            .get(uuid, offset)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs an S3-compatible server"]
+    async fn round_trip() {
+        let store = store();
+        let uuid = Uuid::new_v4();
+        put_bytes(&store, uuid, b"hello, world").await;
+
+        assert_eq!(store.size(uuid).await.unwrap(), 12);
+        assert_eq!(read(&store, uuid, 0).await, b"hello, world");
+        assert_eq!(read(&store, uuid, 7).await, b"world");
+
+        store.delete(uuid).await.unwrap();
+        let e = store.size(uuid).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        // Deleting a missing blob isn't an error
+        store.delete(uuid).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs an S3-compatible server"]
+    async fn empty_blob() {
+        let store = store();
+        let uuid = Uuid::new_v4();
+        put_bytes(&store, uuid, b"").await;
+
+        assert_eq!(store.size(uuid).await.unwrap(), 0);
+        assert!(read(&store, uuid, 0).await.is_empty());
+        store.delete(uuid).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs an S3-compatible server"]
+    async fn list_and_quarantine() {
+        let store = store();
+        let uuid = Uuid::new_v4();
+        put_bytes(&store, uuid, b"blob").await;
+        let stray = store.prefix.child("not-a-uuid");
+        store
+            .store
+            .put(&stray, PutPayload::from_static(b"stray"))
+            .await
+            .unwrap();
+
+        let listing = store.list().await.unwrap();
+        assert_eq!(listing.blobs, vec![uuid]);
+        assert_eq!(listing.stray, vec![stray.to_string()]);
+
+        store.quarantine(stray.as_ref()).await.unwrap();
+        let listing = store.list().await.unwrap();
+        assert_eq!(listing.blobs, vec![uuid]);
+        assert!(listing.stray.is_empty());
+
+        store.delete(uuid).await.unwrap();
+        let quarantined = store.quarantine_prefix().child("not-a-uuid");
+        store.store.delete(&quarantined).await.unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Refuse uploads before the local disk fills up.
+//!
+//! Even with S3 storage, uploads are written to local disk first: multipart uploads to the
+//! system temporary directory, and resumable uploads to the staging area.
+
+use crate::api_error::ApiError;
+use rocket::Request;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use std::path::PathBuf;
+
+pub struct Capacity {
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+pub struct DiskSpace {
+    paths: Vec<PathBuf>,
+    min_free_space: u64,
+}
+
+impl DiskSpace {
+    pub fn new(paths: Vec<PathBuf>, min_free_space: u64) -> Self {
+        Self {
+            paths,
+            min_free_space,
+        }
+    }
+
+    pub fn min_free_space(&self) -> u64 {
+        self.min_free_space
+    }
+
+    /// The capacity of whichever volume has the least space available.
+    pub fn capacity(&self) -> std::io::Result<Capacity> {
+        let mut ret: Option<Capacity> = None;
+        for path in &self.paths {
+            std::fs::create_dir_all(path)?;
+            let stats = fs4::statvfs(path)?;
+            if ret
+                .as_ref()
+                .is_none_or(|x| stats.available_space() < x.available_bytes)
+            {
+                ret = Some(Capacity {
+                    available_bytes: stats.available_space(),
+                    total_bytes: stats.total_space(),
+                });
+            }
+        }
+        Ok(ret.unwrap_or(Capacity {
+            available_bytes: 0,
+            total_bytes: 0,
+        }))
+    }
+
+    /// Whether `incoming` more bytes would leave at least `min_free_space`.
+    pub fn has_room_for(&self, incoming: u64) -> std::io::Result<bool> {
+        let available = self.capacity()?.available_bytes;
+        Ok(available.saturating_sub(incoming) >= self.min_free_space && available >= incoming)
+    }
+}
+
+/// Request guard for routes that accept uploads.
+///
+/// Request guards run before the data guard, so this refuses the request before the body is
+/// read. The incoming size is the larger of `Content-Length` and the tus `Upload-Length`.
+pub struct HasDiskSpace;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HasDiskSpace {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(disk_space) = request.rocket().state::<DiskSpace>() else {
+            return Outcome::Success(Self);
+        };
+        let header = |name: &str| {
+            request
+                .headers()
+                .get_one(name)
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        let incoming = header("Content-Length").max(header("Upload-Length"));
+        match disk_space.has_room_for(incoming) {
+            Ok(true) => Outcome::Success(Self),
+            Ok(false) => Outcome::Error((
+                Status::InsufficientStorage,
+                ApiError::InsufficientStorageError(),
+            )),
+            Err(e) => Outcome::Error((Status::InternalServerError, e.into())),
+        }
+    }
+}
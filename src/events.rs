@@ -0,0 +1,77 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! In-process notifications of changes to a user's files and clips, for `/api/events` and
+//! `/api/files/wait`.
+//!
+//! Events only carry the file's UUID; they aren't filtered by passkey, so clients should
+//! re-fetch the list rather than assume that an added file is visible to them.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use ts_rs::TS;
+use uuid::Uuid;
+
+/// Subscribers that fall this far behind miss events, and are told to re-fetch instead.
+const CAPACITY: usize = 1024;
+
+#[derive(Serialize, TS, Clone, Debug)]
+#[ts(export_to = "api/events/FileEvent.ts")]
+#[serde(crate = "rocket::serde", tag = "type")]
+pub enum FileEvent {
+    #[serde(rename = "file-added")]
+    Added { uuid: Uuid },
+    #[serde(rename = "file-deleted")]
+    Deleted { uuid: Uuid },
+    /// A download was acknowledged
+    #[serde(rename = "file-downloaded")]
+    Downloaded { uuid: Uuid },
+    /// Removed by `prune` because it expired or has no downloads left
+    #[serde(rename = "file-expired")]
+    Expired { uuid: Uuid },
+}
+
+impl FileEvent {
+    /// The SSE event name, which matches the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileEvent::Added { .. } => "file-added",
+            FileEvent::Deleted { .. } => "file-deleted",
+            FileEvent::Downloaded { .. } => "file-downloaded",
+            FileEvent::Expired { .. } => "file-expired",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub user_id: i64,
+    pub event: FileEvent,
+}
+
+#[derive(Clone)]
+pub struct FileEvents {
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Default for FileEvents {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl FileEvents {
+    /// Nobody may be listening, so this can't fail.
+    pub fn publish(&self, user_id: i64, event: FileEvent) {
+        self.sender.send(Notification { user_id, event }).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Server-side knowledge of the encrypted file formats.
+//!
+//! The server never sees keys, but it can check that a ciphertext is well-framed, so that an
+//! upload that could never be decrypted is rejected up front.
+//!
+//! | Version | Contents                                 | Metadata                   |
+//! |---------|------------------------------------------|----------------------------|
+//! | 1       | A single AES-GCM ciphertext of the file  | The filename               |
+//! | 2       | Chunked AES-GCM, as described below      | The filename               |
+//! | 3       | Chunked AES-GCM, as described below      | A JSON object (see below)  |
+//!
+//! In all versions, the metadata is encrypted with AES-GCM using `metadata_iv` as the nonce.
+//! Clips are always version 1.
+//!
+//! For version 1, the file contents are encrypted using `data_iv` as the nonce. Later versions
+//! use a 12-byte header followed by one or more segments:
+//!
+//! | Offset | Size | Content                                   |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 4    | `TFCE`                                    |
+//! | 4      | 1    | Segment format version (2)                |
+//! | 5      | 3    | Reserved, zero                            |
+//! | 8      | 4    | Plaintext segment size, big-endian `u32`  |
+//!
+//! Each segment is the AES-GCM encryption of `segment size` bytes of plaintext, except for the
+//! final segment, which may be shorter (or even empty). The header is the additional
+//! authenticated data for every segment. The nonce for segment `i` is the first 7 bytes of
+//! `data_iv`, followed by `i` as a big-endian `u32`, then `1` for the final segment or `0`
+//! otherwise; this detects reordering and truncation.
+//!
+//! The version 3 metadata is a UTF-8 JSON object; all fields are optional, and clients must
+//! ignore unknown fields:
+//!
+//! - `name`: the original filename
+//! - `type`: the MIME type
+//! - `size`: the plaintext size, in bytes
+//! - `last_modified`: the original modification time, in milliseconds since the Unix epoch
+//! - `notes`: free-form text from the uploader
+
+use crate::api_error::ApiError;
+use base64::prelude::*;
+use std::io::Read;
+use std::path::Path;
+
+pub const LEGACY_FORMAT_VERSION: i64 = 1;
+pub const CHUNKED_FORMAT_VERSION: i64 = 2;
+pub const METADATA_ENVELOPE_FORMAT_VERSION: i64 = 3;
+
+const CHUNKED_MAGIC: &[u8; 4] = b"TFCE";
+const CHUNKED_SEGMENT_FORMAT_VERSION: u8 = 2;
+const CHUNKED_HEADER_SIZE: u64 = 12;
+const MIN_SEGMENT_SIZE: u64 = 4 * 1024;
+const MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+pub const GCM_TAG_SIZE: u64 = 16;
+pub const SALT_SIZE: usize = 16;
+pub const IV_SIZE: usize = 12;
+
+fn invalid(reason: &str) -> ApiError {
+    ApiError::BadRequestError(format!("Invalid ciphertext: {}", reason))
+}
+
+fn validate_chunked(path: &Path) -> Result<(), ApiError> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < CHUNKED_HEADER_SIZE + GCM_TAG_SIZE {
+        return Err(invalid("too short"));
+    }
+
+    let mut header = [0u8; CHUNKED_HEADER_SIZE as usize];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != CHUNKED_MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    if header[4] != CHUNKED_SEGMENT_FORMAT_VERSION {
+        return Err(invalid("unsupported segment format"));
+    }
+    if header[5..8] != [0, 0, 0] {
+        return Err(invalid("reserved header bytes are set"));
+    }
+    let segment_size = u64::from(u32::from_be_bytes(header[8..12].try_into().unwrap()));
+    if !(MIN_SEGMENT_SIZE..=MAX_SEGMENT_SIZE).contains(&segment_size) {
+        return Err(invalid("unsupported segment size"));
+    }
+
+    // Every segment but the last is exactly `segment_size + tag`; the last one
+    // is at least a tag, and at most a full segment.
+    let remainder = (len - CHUNKED_HEADER_SIZE) % (segment_size + GCM_TAG_SIZE);
+    if remainder != 0 && remainder < GCM_TAG_SIZE {
+        return Err(invalid("truncated segment"));
+    }
+    Ok(())
+}
+
+fn decode_base64(field: &'static str, value: &str) -> Result<Vec<u8>, ApiError> {
+    BASE64_STANDARD
+        .decode(value)
+        .map_err(|_| ApiError::invalid_field(field, "not valid base64"))
+}
+
+fn validate_length(field: &'static str, value: &str, expected: usize) -> Result<(), ApiError> {
+    let len = decode_base64(field, value)?.len();
+    if len != expected {
+        return Err(ApiError::invalid_field(
+            field,
+            format!("expected {} bytes, got {}", expected, len),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the parameters a client will need to decrypt the file.
+pub fn validate_crypto_params(
+    salt: &str,
+    metadata_iv: &str,
+    data_iv: &str,
+    encrypted_metadata: &str,
+) -> Result<(), ApiError> {
+    validate_length("salt", salt, SALT_SIZE)?;
+    validate_length("metadata_iv", metadata_iv, IV_SIZE)?;
+    validate_length("data_iv", data_iv, IV_SIZE)?;
+    if decode_base64("encrypted_metadata", encrypted_metadata)?.len() < GCM_TAG_SIZE as usize {
+        return Err(ApiError::invalid_field(
+            "encrypted_metadata",
+            "shorter than an AES-GCM tag",
+        ));
+    }
+    Ok(())
+}
+
+pub fn is_supported_format_version(format_version: i64) -> bool {
+    (LEGACY_FORMAT_VERSION..=METADATA_ENVELOPE_FORMAT_VERSION).contains(&format_version)
+}
+
+/// Check that the ciphertext at `path` is plausible for `format_version`.
+///
+/// This reads the file with blocking IO, so it runs on tokio's blocking thread pool.
+pub async fn validate_framing(path: &Path, format_version: i64) -> Result<(), ApiError> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || validate_framing_blocking(&path, format_version))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn validate_framing_blocking(path: &Path, format_version: i64) -> Result<(), ApiError> {
+    match format_version {
+        LEGACY_FORMAT_VERSION => {
+            if std::fs::metadata(path)?.len() < GCM_TAG_SIZE {
+                return Err(invalid("too short"));
+            }
+            Ok(())
+        }
+        CHUNKED_FORMAT_VERSION | METADATA_ENVELOPE_FORMAT_VERSION => validate_chunked(path),
+        _ => Err(ApiError::invalid_field("format_version", "unsupported")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const SEGMENT_SIZE: u32 = 4096;
+
+    fn header(segment_size: u32) -> Vec<u8> {
+        let mut header = CHUNKED_MAGIC.to_vec();
+        header.extend([CHUNKED_SEGMENT_FORMAT_VERSION, 0, 0, 0]);
+        header.extend(segment_size.to_be_bytes());
+        header
+    }
+
+    /// A header, then `full_segments` full segments, then a final segment of `last` bytes
+    /// including its tag.
+    fn chunked(segment_size: u32, full_segments: usize, last: usize) -> Vec<u8> {
+        let mut data = header(segment_size);
+        let full = segment_size as usize + GCM_TAG_SIZE as usize;
+        data.resize(data.len() + full * full_segments + last, 0);
+        data
+    }
+
+    async fn validate(data: &[u8], format_version: i64) -> Result<(), ApiError> {
+        let path = std::env::temp_dir().join(format!("tempfiles-test-{}", Uuid::new_v4()));
+        std::fs::write(&path, data).unwrap();
+        let result = validate_framing(&path, format_version).await;
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn is_invalid(result: Result<(), ApiError>, reason: &str) -> bool {
+        matches!(result, Err(ApiError::BadRequestError(e)) if e.ends_with(reason))
+    }
+
+    #[rocket::async_test]
+    async fn legacy_framing() {
+        assert!(
+            validate(&[0; GCM_TAG_SIZE as usize], LEGACY_FORMAT_VERSION)
+                .await
+                .is_ok()
+        );
+        assert!(is_invalid(
+            validate(&[0; GCM_TAG_SIZE as usize - 1], LEGACY_FORMAT_VERSION).await,
+            "too short"
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn chunked_framing() {
+        let tag = GCM_TAG_SIZE as usize;
+        for version in [CHUNKED_FORMAT_VERSION, METADATA_ENVELOPE_FORMAT_VERSION] {
+            // An empty final segment is just a tag
+            assert!(
+                validate(&chunked(SEGMENT_SIZE, 0, tag), version)
+                    .await
+                    .is_ok()
+            );
+            assert!(
+                validate(&chunked(SEGMENT_SIZE, 0, tag + 1), version)
+                    .await
+                    .is_ok()
+            );
+            assert!(
+                validate(&chunked(SEGMENT_SIZE, 2, 0), version)
+                    .await
+                    .is_ok()
+            );
+            assert!(
+                validate(&chunked(SEGMENT_SIZE, 2, tag + 100), version)
+                    .await
+                    .is_ok()
+            );
+            assert!(is_invalid(
+                validate(&chunked(SEGMENT_SIZE, 2, tag - 1), version).await,
+                "truncated segment"
+            ));
+            assert!(is_invalid(
+                validate(&header(SEGMENT_SIZE), version).await,
+                "too short"
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn chunked_header() {
+        let tag = GCM_TAG_SIZE as usize;
+        let version = CHUNKED_FORMAT_VERSION;
+
+        let mut data = chunked(SEGMENT_SIZE, 0, tag);
+        data[0] = b'X';
+        assert!(is_invalid(validate(&data, version).await, "bad magic"));
+
+        let mut data = chunked(SEGMENT_SIZE, 0, tag);
+        data[4] = 3;
+        assert!(is_invalid(
+            validate(&data, version).await,
+            "unsupported segment format"
+        ));
+
+        let mut data = chunked(SEGMENT_SIZE, 0, tag);
+        data[6] = 1;
+        assert!(is_invalid(
+            validate(&data, version).await,
+            "reserved header bytes are set"
+        ));
+
+        for segment_size in [MIN_SEGMENT_SIZE - 1, MAX_SEGMENT_SIZE + 1] {
+            assert!(is_invalid(
+                validate(&chunked(segment_size as u32, 0, tag), version).await,
+                "unsupported segment size"
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn unsupported_version() {
+        assert!(matches!(
+            validate(&[0; 64], 4).await,
+            Err(ApiError::InvalidFieldError(e)) if e.field == "format_version"
+        ));
+    }
+
+    #[test]
+    fn crypto_params() {
+        let salt = BASE64_STANDARD.encode([0; SALT_SIZE]);
+        let iv = BASE64_STANDARD.encode([0; IV_SIZE]);
+        let metadata = BASE64_STANDARD.encode([0; GCM_TAG_SIZE as usize]);
+        assert!(validate_crypto_params(&salt, &iv, &iv, &metadata).is_ok());
+
+        let invalid_field = |result: Result<(), ApiError>| match result {
+            Err(ApiError::InvalidFieldError(e)) => e.field,
+            _ => panic!("expected an invalid field"),
+        };
+        let short = BASE64_STANDARD.encode([0; 8]);
+        assert_eq!(
+            invalid_field(validate_crypto_params(&short, &iv, &iv, &metadata)),
+            "salt"
+        );
+        assert_eq!(
+            invalid_field(validate_crypto_params(&salt, &salt, &iv, &metadata)),
+            "metadata_iv"
+        );
+        assert_eq!(
+            invalid_field(validate_crypto_params(&salt, &iv, "not base64!", &metadata)),
+            "data_iv"
+        );
+        let metadata = BASE64_STANDARD.encode([0; GCM_TAG_SIZE as usize - 1]);
+        assert_eq!(
+            invalid_field(validate_crypto_params(&salt, &iv, &iv, &metadata)),
+            "encrypted_metadata"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! What happened to each file, when, and from where.
+//!
+//! History is kept after the file itself is gone, until it's older than
+//! `prune.history_retention`, so that users can see whether a one-time file was collected.
+
+use crate::session::Session;
+use crate::webhooks;
+use rocket::Request;
+use rocket::request::{FromRequest, Outcome};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, query};
+use std::convert::Infallible;
+use ts_rs::TS;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, TS, Clone, Copy)]
+#[ts(export_to = "api/FileAction.ts")]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum FileAction {
+    Upload,
+    /// A download was acknowledged
+    Download,
+    Delete,
+    /// Removed by `prune`
+    Expire,
+}
+
+impl FileAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileAction::Upload => "upload",
+            FileAction::Download => "download",
+            FileAction::Delete => "delete",
+            FileAction::Expire => "expire",
+        }
+    }
+}
+
+/// Where a request came from; this never fails, as either part may be unknown.
+pub struct RequestOrigin {
+    /// Respects Rocket's `ip_header` setting, for servers behind a reverse proxy
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestOrigin {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self {
+            ip: request.client_ip().map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+        })
+    }
+}
+
+/// Record an action taken on a file, and queue any webhooks for it; `by` is `None` for the
+/// server's own actions.
+pub async fn record(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    file_uuid: Uuid,
+    action: FileAction,
+    by: Option<(&Session, &RequestOrigin)>,
+) -> sqlx::Result<()> {
+    let passkey_id = by.map(|(session, _)| session.passkey_id());
+    let device = by.and_then(|(session, _)| session.device());
+    let device_id = device.map(|device| device.id);
+    let ip = by.and_then(|(_, origin)| origin.ip.as_deref());
+    let user_agent = by.and_then(|(_, origin)| origin.user_agent.as_deref());
+    let action_name = action.as_str();
+    // The passkey or device may have been deleted since the session started
+    query!(
+        r#"
+        INSERT INTO file_events (user_id, file_uuid, action, passkey_id, device_id, ip, user_agent)
+        VALUES (?1, ?2, ?3, (SELECT id FROM passkeys WHERE id = ?4),
+        (SELECT id FROM devices WHERE id = ?5), ?6, ?7)
+        "#,
+        user_id,
+        file_uuid,
+        action_name,
+        passkey_id,
+        device_id,
+        ip,
+        user_agent,
+    )
+    .execute(&mut *conn)
+    .await?;
+    webhooks::enqueue(
+        conn,
+        user_id,
+        action,
+        file_uuid,
+        device.map(|device| device.uuid),
+    )
+    .await
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/FileHistoryEntry.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct FileHistoryEntry {
+    pub file_uuid: Uuid,
+    #[ts(type = "'upload' | 'download' | 'delete' | 'expire'")]
+    pub action: String,
+    #[ts(type = "number")]
+    pub created_at: i64,
+    /// Unset for actions taken by the server, such as expiry
+    #[ts(type = "number | null")]
+    pub passkey_id: Option<i64>,
+    pub device: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// The user's history, newest first, optionally for a single file.
+pub async fn entries(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    file_uuid: Option<Uuid>,
+    limit: Option<i64>,
+) -> sqlx::Result<Vec<FileHistoryEntry>> {
+    let rows = query!(
+        r#"
+        SELECT file_uuid AS "file_uuid: Uuid", action, created_at, passkey_id, ip, user_agent,
+        (SELECT uuid FROM devices WHERE id = device_id) AS "device?: Uuid"
+        FROM file_events
+        WHERE user_id = ?1 AND (?2 IS NULL OR file_uuid = ?2)
+        ORDER BY id DESC
+        LIMIT COALESCE(?3, -1)
+        "#,
+        user_id,
+        file_uuid,
+        limit,
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| FileHistoryEntry {
+            file_uuid: row.file_uuid,
+            action: row.action,
+            created_at: row.created_at.and_utc().timestamp(),
+            passkey_id: row.passkey_id,
+            device: row.device,
+            ip: row.ip,
+            user_agent: row.user_agent,
+        })
+        .collect())
+}
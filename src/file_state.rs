@@ -0,0 +1,259 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! The lifecycle of a row in `files`: `pending` → `live` → `tombstoned` → purged.
+//!
+//! - `pending`: the row has been inserted, but the blob may not have been stored yet; clips
+//!   and small files are stored inline in the row, so they have no blob and start out `live`
+//! - `live`: visible to `list` and downloads, unless it's in the trash
+//! - `tombstoned`: hidden; the blob and then the row still need to be removed
+//! - purged: the row no longer exists
+//!
+//! The row is always written before the blob, and the blob removed before the row, so every
+//! blob in the store is referenced by a row until it's purged. If any step fails or the server
+//! stops partway, `prune` finishes the job: it tombstones `pending` rows that are more than a
+//! day old, then purges every tombstone.
+
+use crate::blob_store::BlobStore;
+use log::warn;
+use sqlx::{SqliteConnection, query};
+use uuid::Uuid;
+
+/// Returns false if the row is no longer pending, e.g. because it was pruned.
+pub async fn mark_live(conn: &mut SqliteConnection, uuid: Uuid) -> sqlx::Result<bool> {
+    let result = query!(
+        "UPDATE files SET state = 'live' WHERE uuid = ?1 AND state = 'pending'",
+        uuid
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns false if the file doesn't exist, or has already been tombstoned.
+pub async fn tombstone(conn: &mut SqliteConnection, uuid: Uuid) -> sqlx::Result<bool> {
+    let result = query!(
+        "UPDATE files SET state = 'tombstoned' WHERE uuid = ?1 AND state != 'tombstoned'",
+        uuid
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Remove a tombstoned file's blob, if it has one, then its row.
+pub async fn purge(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    uuid: Uuid,
+) -> anyhow::Result<()> {
+    let is_inline = query!(
+        r#"SELECT inline_data IS NOT NULL AS "is_inline!: bool" FROM files WHERE uuid = ?1"#,
+        uuid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .is_some_and(|row| row.is_inline);
+    if !is_inline {
+        store.delete(uuid).await?;
+    }
+    query!(
+        "DELETE FROM files WHERE uuid = ?1 AND state = 'tombstoned'",
+        uuid
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Like `purge()`, but failures are logged rather than returned; `prune` will try again.
+///
+/// Returns whether the file was purged.
+pub async fn purge_or_defer(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    uuid: Uuid,
+) -> bool {
+    match purge(conn, store, uuid).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to purge {}, leaving it for prune: {}", uuid, e);
+            false
+        }
+    }
+}
+
+/// Purge every tombstoned file, or only those belonging to `user_id`.
+///
+/// Returns the files that were purged; failures are logged and skipped.
+pub async fn purge_tombstones(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    user_id: Option<i64>,
+) -> sqlx::Result<Vec<Uuid>> {
+    let rows = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid" FROM files
+        WHERE state = 'tombstoned' AND (?1 IS NULL OR user_id = ?1)
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut purged = vec![];
+    for row in rows {
+        if purge_or_defer(conn, store, row.uuid).await {
+            purged.push(row.uuid);
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_db::test_db;
+    use crate::blob_store::LocalBlobStore;
+    use std::path::PathBuf;
+
+    async fn create_user(conn: &mut SqliteConnection) -> i64 {
+        let uuid = Uuid::new_v4().to_string();
+        query!(
+            "INSERT INTO users (username, uuid) VALUES (?1, ?1) RETURNING id",
+            uuid
+        )
+        .fetch_one(conn)
+        .await
+        .unwrap()
+        .id
+    }
+
+    async fn create_file(
+        conn: &mut SqliteConnection,
+        user_id: i64,
+        state: &str,
+        inline_data: Option<&[u8]>,
+    ) -> Uuid {
+        let uuid = Uuid::new_v4();
+        query!(
+            r#"
+            INSERT INTO files (user_id, uuid, metadata_iv, data_iv, encrypted_metadata, state,
+            inline_data)
+            VALUES (?1, ?2, '', '', '', ?3, ?4)
+            "#,
+            user_id,
+            uuid,
+            state,
+            inline_data,
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+        uuid
+    }
+
+    async fn state(conn: &mut SqliteConnection, uuid: Uuid) -> Option<String> {
+        query!("SELECT state FROM files WHERE uuid = ?1", uuid)
+            .fetch_optional(conn)
+            .await
+            .unwrap()
+            .map(|row| row.state)
+    }
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("tempfiles-test-{}", Uuid::new_v4()))
+    }
+
+    async fn put_blob(store: &LocalBlobStore, root: &std::path::Path, uuid: Uuid) {
+        tokio::fs::create_dir_all(root).await.unwrap();
+        let source = root.join(format!("upload-{}", uuid));
+        tokio::fs::write(&source, b"blob").await.unwrap();
+        store.put(uuid, &source).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn mark_live_only_changes_pending_files() {
+        let mut conn = test_db().await;
+        let user_id = create_user(&mut conn).await;
+        let uuid = create_file(&mut conn, user_id, "pending", None).await;
+
+        assert!(mark_live(&mut conn, uuid).await.unwrap());
+        assert_eq!(state(&mut conn, uuid).await.as_deref(), Some("live"));
+        assert!(!mark_live(&mut conn, uuid).await.unwrap());
+
+        // e.g. `prune` got there first
+        let uuid = create_file(&mut conn, user_id, "tombstoned", None).await;
+        assert!(!mark_live(&mut conn, uuid).await.unwrap());
+        assert_eq!(state(&mut conn, uuid).await.as_deref(), Some("tombstoned"));
+
+        assert!(!mark_live(&mut conn, Uuid::new_v4()).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn tombstone_is_idempotent() {
+        let mut conn = test_db().await;
+        let user_id = create_user(&mut conn).await;
+        for initial in ["pending", "live"] {
+            let uuid = create_file(&mut conn, user_id, initial, None).await;
+            assert!(tombstone(&mut conn, uuid).await.unwrap());
+            assert_eq!(state(&mut conn, uuid).await.as_deref(), Some("tombstoned"));
+            assert!(!tombstone(&mut conn, uuid).await.unwrap());
+        }
+        assert!(!tombstone(&mut conn, Uuid::new_v4()).await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn purge_removes_blob_then_row() {
+        let mut conn = test_db().await;
+        let root = temp_root();
+        let store = LocalBlobStore::new(&root, 2);
+        let user_id = create_user(&mut conn).await;
+
+        let uuid = create_file(&mut conn, user_id, "tombstoned", None).await;
+        put_blob(&store, &root, uuid).await;
+        purge(&mut conn, &store, uuid).await.unwrap();
+        assert_eq!(state(&mut conn, uuid).await, None);
+        let e = store.size(uuid).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+        // Inline files have no blob to remove
+        let uuid = create_file(&mut conn, user_id, "tombstoned", Some(b"inline")).await;
+        purge(&mut conn, &store, uuid).await.unwrap();
+        assert_eq!(state(&mut conn, uuid).await, None);
+
+        // Only tombstoned rows are removed
+        let uuid = create_file(&mut conn, user_id, "live", Some(b"inline")).await;
+        purge(&mut conn, &store, uuid).await.unwrap();
+        assert_eq!(state(&mut conn, uuid).await.as_deref(), Some("live"));
+
+        tokio::fs::remove_dir_all(&root).await.ok();
+    }
+
+    #[rocket::async_test]
+    async fn purge_tombstones_for_one_user() {
+        let mut conn = test_db().await;
+        let root = temp_root();
+        let store = LocalBlobStore::new(&root, 0);
+        let alice = create_user(&mut conn).await;
+        let bob = create_user(&mut conn).await;
+        let alice_file = create_file(&mut conn, alice, "tombstoned", Some(b"a")).await;
+        let alice_live = create_file(&mut conn, alice, "live", Some(b"a")).await;
+        let bob_file = create_file(&mut conn, bob, "tombstoned", Some(b"b")).await;
+
+        let purged = purge_tombstones(&mut conn, &store, Some(alice))
+            .await
+            .unwrap();
+        assert_eq!(purged, vec![alice_file]);
+        assert_eq!(state(&mut conn, alice_live).await.as_deref(), Some("live"));
+        assert_eq!(
+            state(&mut conn, bob_file).await.as_deref(),
+            Some("tombstoned")
+        );
+
+        let purged = purge_tombstones(&mut conn, &store, None).await.unwrap();
+        assert_eq!(purged, vec![bob_file]);
+    }
+}
@@ -7,17 +7,31 @@
 mod api_error;
 mod app_db;
 mod app_html;
+mod blob_store;
+mod disk_space;
+mod events;
+mod file_format;
+mod file_history;
+mod file_state;
+mod migrations;
 mod prf_seed;
 mod prune;
+mod quota;
+mod ranged_file;
+mod retention;
 mod routes;
 mod serve;
 mod session;
+mod webhooks;
 
-use crate::prune::prune;
+use crate::blob_store::{MAX_SHARD_DEPTH, StorageConfig};
+use crate::events::FileEvents;
+use crate::prune::{PruneConfig, prune};
 use clap::{Parser, Subcommand};
+use rocket::data::ByteUnit;
 use sqlx::query;
 use std::io::{Write, stdin, stdout};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[macro_use]
 extern crate rocket;
@@ -106,7 +120,52 @@ enum Commands {
     },
     Serve,
     GenTS,
-    Prune,
+    Migrate,
+    /// Move files stored by an earlier `storage` configuration to the current one
+    MigrateStorage {
+        /// The previous `storage.root`
+        #[arg(long)]
+        from_root: PathBuf,
+        /// The previous `storage.shard_depth`
+        #[arg(long, default_value_t = 2)]
+        from_shard_depth: usize,
+    },
+    /// Remove expired files, and storage that isn't referenced by the database
+    Prune {
+        /// Report what would be removed, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Override the default quotas for a user; omit a limit to use the default again
+    SetQuota {
+        username: String,
+        /// e.g. `10GiB`
+        #[arg(long, value_parser = parse_byte_unit)]
+        max_bytes: Option<ByteUnit>,
+        #[arg(long)]
+        max_files: Option<i64>,
+    },
+    /// Override the default retention policy for a user; omit a setting to use the default again
+    SetRetention {
+        username: String,
+        /// Seconds
+        #[arg(long)]
+        default_expires_in: Option<i64>,
+        /// Seconds
+        #[arg(long)]
+        max_expires_in: Option<i64>,
+        #[arg(long)]
+        require_max_downloads: Option<bool>,
+    },
+}
+
+fn parse_byte_unit(value: &str) -> Result<ByteUnit, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", value))
 }
 
 fn generate_typescript() {
@@ -115,10 +174,99 @@ fn generate_typescript() {
     println!("Generated TypeScript files.");
 }
 
-async fn prune_main() -> anyhow::Result<()> {
+async fn migrate_main() -> anyhow::Result<()> {
+    let mut db = unpooled_db().await?;
+    migrations::migrate(&mut db).await?;
+    let store = StorageConfig::from_figment(&rocket::Config::figment())?.open()?;
+    migrations::backfill_file_sizes(&mut db, store.as_ref()).await
+}
+
+async fn migrate_storage_main(from_root: &Path, from_shard_depth: usize) -> anyhow::Result<()> {
+    let config = StorageConfig::from_figment(&rocket::Config::figment())?;
+    if from_shard_depth > MAX_SHARD_DEPTH {
+        anyhow::bail!("--from-shard-depth must be at most {}", MAX_SHARD_DEPTH);
+    }
+    blob_store::migrate(
+        from_root,
+        from_shard_depth,
+        config.open()?.as_ref(),
+        &config.staging(),
+    )
+    .await
+}
+
+async fn set_quota(
+    username: &str,
+    max_bytes: Option<ByteUnit>,
+    max_files: Option<i64>,
+) -> anyhow::Result<()> {
     let mut db = unpooled_db().await?;
-    let root: PathBuf = "uploads/".into();
-    prune(&mut db, &root).await?;
+    let max_bytes = max_bytes.map(|x| x.as_u64() as i64);
+    let updated = query!(
+        "UPDATE users SET max_bytes = ?1, max_files = ?2 WHERE username = ?3",
+        max_bytes,
+        max_files,
+        username,
+    )
+    .execute(&mut db)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        anyhow::bail!("No such user: {}", username);
+    }
+    println!("Updated quotas for {}.", username);
+    Ok(())
+}
+
+async fn set_retention(
+    username: &str,
+    default_expires_in: Option<i64>,
+    max_expires_in: Option<i64>,
+    require_max_downloads: Option<bool>,
+) -> anyhow::Result<()> {
+    if default_expires_in.is_some_and(|x| x < 1) || max_expires_in.is_some_and(|x| x < 1) {
+        anyhow::bail!("Durations must be at least 1 second");
+    }
+    let mut db = unpooled_db().await?;
+    let updated = query!(
+        r#"
+        UPDATE users SET default_expires_in = ?1, max_expires_in = ?2, require_max_downloads = ?3
+        WHERE username = ?4
+        "#,
+        default_expires_in,
+        max_expires_in,
+        require_max_downloads,
+        username,
+    )
+    .execute(&mut db)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        anyhow::bail!("No such user: {}", username);
+    }
+    println!("Updated retention policy for {}.", username);
+    Ok(())
+}
+
+async fn prune_main(dry_run: bool, json: bool) -> anyhow::Result<()> {
+    let mut db = unpooled_db().await?;
+    let figment = rocket::Config::figment();
+    let storage = StorageConfig::from_figment(&figment)?;
+    let report = prune(
+        &mut db,
+        storage.open()?.as_ref(),
+        &storage.staging(),
+        &PruneConfig::from_figment(&figment)?,
+        // Nobody is listening to a one-off prune
+        &FileEvents::default(),
+        dry_run,
+    )
+    .await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
     Ok(())
 }
 
@@ -131,7 +279,31 @@ async fn main() -> anyhow::Result<()> {
         Commands::AddUser { username, force } => add_user(username, force.to_owned()).await,
         Commands::Serve => serve::serve().await?,
         Commands::GenTS => generate_typescript(),
-        Commands::Prune => prune_main().await?,
+        Commands::Migrate => migrate_main().await?,
+        Commands::MigrateStorage {
+            from_root,
+            from_shard_depth,
+        } => migrate_storage_main(from_root, *from_shard_depth).await?,
+        Commands::Prune { dry_run, json } => prune_main(*dry_run, *json).await?,
+        Commands::SetQuota {
+            username,
+            max_bytes,
+            max_files,
+        } => set_quota(username, *max_bytes, *max_files).await?,
+        Commands::SetRetention {
+            username,
+            default_expires_in,
+            max_expires_in,
+            require_max_downloads,
+        } => {
+            set_retention(
+                username,
+                *default_expires_in,
+                *max_expires_in,
+                *require_max_downloads,
+            )
+            .await?
+        }
     }
     Ok(())
 }
@@ -0,0 +1,79 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::blob_store::BlobStore;
+use sqlx::{Connection, Executor, SqliteConnection, query};
+use uuid::Uuid;
+
+/// Upgrades for databases created from an older `schema.sql`.
+///
+/// The database's `user_version` pragma records how many of these have been applied;
+/// `schema.sql` sets it to `MIGRATIONS.len()`, so new databases skip all of them.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_pending_uploads.sql"),
+    include_str!("../migrations/0002_format_version.sql"),
+    include_str!("../migrations/0003_encrypted_metadata.sql"),
+    include_str!("../migrations/0004_quotas.sql"),
+    include_str!("../migrations/0005_retention.sql"),
+    include_str!("../migrations/0006_trash.sql"),
+    include_str!("../migrations/0007_file_state.sql"),
+    include_str!("../migrations/0008_bundles.sql"),
+    include_str!("../migrations/0009_clips.sql"),
+    include_str!("../migrations/0010_devices.sql"),
+    include_str!("../migrations/0011_file_events.sql"),
+    include_str!("../migrations/0012_webhooks.sql"),
+    include_str!("../migrations/0013_pending_upload_devices.sql"),
+];
+
+pub async fn migrate(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(&mut *conn)
+        .await?;
+    let version = usize::try_from(version)?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {} is newer than this build supports ({})",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let mut tx = conn.begin().await?;
+        tx.execute(*migration).await?;
+        tx.execute(format!("PRAGMA user_version = {}", index + 1).as_str())
+            .await?;
+        tx.commit().await?;
+        println!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+/// Files uploaded before sizes were recorded don't count towards quotas until this is run.
+pub async fn backfill_file_sizes(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+) -> anyhow::Result<()> {
+    let rows = query!(r#"SELECT uuid AS "uuid: Uuid" FROM files WHERE size IS NULL"#)
+        .fetch_all(&mut *conn)
+        .await?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    for row in &rows {
+        let size = match store.size(row.uuid).await {
+            Ok(size) => size as i64,
+            // Left for `prune`
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        query!("UPDATE files SET size = ?1 WHERE uuid = ?2", size, row.uuid)
+            .execute(&mut *conn)
+            .await?;
+    }
+    println!("Recorded sizes of {} existing files", rows.len());
+    Ok(())
+}
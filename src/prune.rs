@@ -3,103 +3,580 @@
  * SPDX-License-Identifier: MIT
  *
  */
+use crate::blob_store::{BlobStore, StagingArea};
+use crate::events::{FileEvent, FileEvents};
+use crate::file_history;
+use crate::file_history::FileAction;
+use crate::file_state;
+use rocket::figment::Figment;
+use rocket::figment::providers::Serialized;
+use serde::{Deserialize, Serialize};
 use sqlx::{SqliteConnection, query};
 use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ts_rs::TS;
 use uuid::Uuid;
 
-fn visit_directory(dir: &Path, visitor: &mut dyn FnMut(&Path)) -> std::io::Result<()> {
-    if !dir.is_dir() {
-        return Ok(());
+/// The `prune` section of `Rocket.toml`, for the server's periodic prune; all in seconds.
+#[derive(Deserialize)]
+pub struct PruneConfig {
+    pub interval: u64,
+    /// The first run is delayed by a random amount up to this, so that several servers
+    /// started together don't all prune at once
+    pub jitter: u64,
+    /// How soon to try again after a failure
+    pub retry_interval: u64,
+    /// If set, users with no passkeys and no valid registration token are deleted this long
+    /// after they were created
+    pub unregistered_user_grace: Option<u64>,
+    /// How long to keep each file's history of uploads, downloads, and deletions
+    pub history_retention: u64,
+}
+
+impl PruneConfig {
+    /// Missing settings default to hourly, starting immediately, and retrying after a minute;
+    /// history is kept for 30 days.
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Self> {
+        let config: Self = figment
+            .clone()
+            .join(Serialized::default(
+                "prune",
+                serde_json::json!({
+                    "interval": 60 * 60,
+                    "jitter": 0,
+                    "retry_interval": 60,
+                    "history_retention": 30 * 24 * 60 * 60,
+                }),
+            ))
+            .extract_inner("prune")?;
+        if config.interval < 1
+            || config.retry_interval < 1
+            || config.history_retention < 1
+            || config.unregistered_user_grace.is_some_and(|x| x < 1)
+        {
+            anyhow::bail!("prune intervals must be at least 1 second");
+        }
+        Ok(config)
     }
+}
 
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+/// The outcome of the server's periodic prunes, for `/api/health`.
+#[derive(Serialize, TS, Clone, Default)]
+#[ts(export_to = "api/PruneStatus.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct PruneStatus {
+    /// Unix timestamp of when the last run started
+    #[ts(type = "number | null")]
+    pub last_run_at: Option<i64>,
+    #[ts(type = "number | null")]
+    pub last_duration_ms: Option<u64>,
+    /// Rows, blobs, and files removed or quarantined by the last run
+    #[ts(type = "number")]
+    pub last_removed: u64,
+    /// Set if the last run failed
+    pub last_error: Option<String>,
+    #[ts(type = "number | null")]
+    pub last_success_at: Option<i64>,
+    #[ts(type = "number")]
+    pub runs: u64,
+    #[ts(type = "number")]
+    pub failures: u64,
+}
 
-        if path.is_dir() {
-            visit_directory(&path, visitor)?;
+#[derive(Default)]
+pub struct PruneMetrics(Mutex<PruneStatus>);
+
+impl PruneMetrics {
+    pub fn status(&self) -> PruneStatus {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn record(
+        &self,
+        started_at: SystemTime,
+        duration: Duration,
+        result: &anyhow::Result<PruneReport>,
+    ) {
+        let started_at = started_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        let mut status = self.0.lock().unwrap();
+        status.last_run_at = Some(started_at);
+        status.last_duration_ms = Some(duration.as_millis() as u64);
+        status.runs += 1;
+        match result {
+            Ok(report) => {
+                status.last_removed = report.removed();
+                status.last_error = None;
+                status.last_success_at = Some(started_at);
+            }
+            Err(e) => {
+                status.last_removed = 0;
+                status.last_error = Some(e.to_string());
+                status.failures += 1;
+            }
         }
+    }
+}
 
-        visitor(&path);
+/// What `prune()` removed, or would have removed for a dry run.
+#[derive(Serialize, Default)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    /// Files that were tombstoned because they expired, have no downloads left, have been in the
+    /// trash for longer than `retention.trash_period`, or were never completed
+    pub expired_files: Vec<Uuid>,
+    /// Tombstoned files whose blob and row were removed; includes `expired_files`
+    pub purged_files: Vec<Uuid>,
+    /// Bundles that expired, have no downloads left, or have been empty for a day
+    pub expired_bundles: Vec<Uuid>,
+    /// Resumable uploads that haven't received any data for a day
+    pub expired_uploads: Vec<Uuid>,
+    /// Blobs that aren't referenced by any file or resumable upload
+    pub orphan_blobs: Vec<Uuid>,
+    /// Files in the staging area that aren't referenced by a live resumable upload
+    pub orphan_uploads: Vec<Uuid>,
+    /// Live files without a blob; these are reported, but not removed
+    pub missing_blobs: Vec<Uuid>,
+    /// Unexpected files in storage, which are quarantined rather than deleted
+    pub stray_files: Vec<String>,
+    /// Usernames whose registration token has expired
+    pub expired_tokens: Vec<String>,
+    /// Users that never registered a passkey; see `PruneConfig::unregistered_user_grace`
+    pub unregistered_users: Vec<String>,
+    /// File history entries older than `PruneConfig::history_retention`
+    pub expired_history: u64,
+    /// Finished webhook deliveries older than `PruneConfig::history_retention`
+    pub expired_deliveries: u64,
+}
+
+impl PruneReport {
+    /// The number of items removed or quarantined; missing blobs aren't counted.
+    pub fn removed(&self) -> u64 {
+        (self.purged_files.len()
+            + self.expired_bundles.len()
+            + self.expired_uploads.len()
+            + self.orphan_blobs.len()
+            + self.orphan_uploads.len()
+            + self.stray_files.len()
+            + self.expired_tokens.len()
+            + self.unregistered_users.len()) as u64
+            + self.expired_history
+            + self.expired_deliveries
     }
-    Ok(())
 }
 
-fn list_recursive_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
-    let mut ret: Vec<PathBuf> = vec![];
-    visit_directory(dir, &mut |path| {
-        if path.is_file() {
-            ret.push(path.to_path_buf())
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (delete, quarantine) = if self.dry_run {
+            ("Would delete", "Would quarantine")
+        } else {
+            ("Deleted", "Quarantined")
+        };
+        let tombstone = if self.dry_run {
+            "Would tombstone"
+        } else {
+            "Tombstoned"
+        };
+        for uuid in &self.expired_files {
+            writeln!(f, "{} expired file: {}", tombstone, uuid)?;
+        }
+        for uuid in &self.purged_files {
+            writeln!(f, "{} file: {}", delete, uuid)?;
+        }
+        for uuid in &self.expired_bundles {
+            writeln!(f, "{} expired bundle: {}", delete, uuid)?;
+        }
+        for uuid in &self.expired_uploads {
+            writeln!(f, "{} expired incomplete upload: {}", delete, uuid)?;
+        }
+        for uuid in &self.orphan_blobs {
+            writeln!(f, "{} orphan blob: {}", delete, uuid)?;
+        }
+        for uuid in &self.orphan_uploads {
+            writeln!(f, "{} orphan incomplete upload: {}", delete, uuid)?;
+        }
+        for uuid in &self.missing_blobs {
+            writeln!(f, "Missing blob for file: {}", uuid)?;
+        }
+        for path in &self.stray_files {
+            writeln!(f, "{} unexpected file: {}", quarantine, path)?;
+        }
+        for username in &self.expired_tokens {
+            writeln!(f, "{} expired registration token for: {}", delete, username)?;
         }
-    })?;
-    Ok(ret)
+        for username in &self.unregistered_users {
+            writeln!(f, "{} unregistered user: {}", delete, username)?;
+        }
+        if self.expired_deliveries > 0 {
+            writeln!(
+                f,
+                "{} {} expired webhook deliveries",
+                delete, self.expired_deliveries
+            )?;
+        }
+        if self.expired_history > 0 {
+            writeln!(
+                f,
+                "{} {} expired file history entries",
+                delete, self.expired_history
+            )?;
+        }
+        Ok(())
+    }
 }
 
-async fn prune_files(conn: &mut SqliteConnection, upload_root: &Path) -> anyhow::Result<()> {
-    let files = list_recursive_files(upload_root)?;
-    let live_uuids = query!(
+/// Live files that are stored in the blob store, rather than inline.
+async fn live_blob_files(conn: &mut SqliteConnection) -> sqlx::Result<HashSet<Uuid>> {
+    Ok(query!(
+        r#"SELECT uuid AS "uuid: Uuid" FROM files WHERE state = 'live' AND inline_data IS NULL"#
+    )
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|r| r.uuid)
+    .collect())
+}
+
+/// Remove blobs that aren't referenced by any file, and report live files without a blob.
+///
+/// Files stored inline never have a blob, so they are never reported as missing one.
+///
+/// Blobs for uploads that are still being finished are left alone.
+async fn prune_blobs(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    report: &mut PruneReport,
+) -> anyhow::Result<()> {
+    // Blobs are stored before their row goes live, so a file that's live before the listing
+    // started has its blob in the listing; one that's still live afterwards hasn't been purged
+    // in the meantime. Files that are live both times, but aren't listed, are missing.
+    let live_before = live_blob_files(&mut *conn).await?;
+    // Rows are written before blobs, so as long as the rows are read after the listing, a blob
+    // without a row can't be part of an upload that's in progress; rows being purged are handled
+    // by `prune_file_rows()`.
+    let listing = store.list().await?;
+    let known_uuids = query!(
         r#"
         SELECT uuid AS "uuid: Uuid" FROM files
-        WHERE
-        (salt IS NOT NULL)
-        AND (downloads_remaining IS NULL OR downloads_remaining > 0)
-        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        UNION
+        SELECT uuid AS "uuid: Uuid" FROM pending_uploads
         "#
     )
-    .fetch_all(conn)
+    .fetch_all(&mut *conn)
     .await?
     .iter()
     .map(|r| r.uuid)
     .collect::<HashSet<Uuid>>();
-    for file in files {
-        let file_name = file.file_name().expect("File without filename");
-        let uuid = Uuid::parse_str(file_name.to_str().unwrap())?;
-        if live_uuids.contains(&uuid) {
+    let live_after = live_blob_files(conn).await?;
+    let live_files = live_before
+        .intersection(&live_after)
+        .copied()
+        .collect::<HashSet<Uuid>>();
+
+    let blobs = listing.blobs.iter().copied().collect::<HashSet<Uuid>>();
+    for uuid in listing.blobs {
+        if known_uuids.contains(&uuid) {
             continue;
         }
-        std::fs::remove_file(&file)?;
-        println!("Deleted file: {}", uuid)
+        if !report.dry_run {
+            store.delete(uuid).await?;
+        }
+        report.orphan_blobs.push(uuid);
+    }
+    report
+        .missing_blobs
+        .extend(live_files.difference(&blobs).copied());
+    for stray in listing.stray {
+        if !report.dry_run {
+            store.quarantine(&stray).await?;
+        }
+        report.stray_files.push(stray);
     }
     Ok(())
 }
 
-fn delete_empty_directories(path: &Path) -> std::io::Result<()> {
-    if !path.is_dir() {
+/// Tombstone files that are no longer wanted, then purge every tombstone.
+///
+/// Tombstones can also be left behind by `delete` or `upload` if a step failed.
+async fn prune_file_rows(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    events: &FileEvents,
+    report: &mut PruneReport,
+) -> sqlx::Result<()> {
+    let rows = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid", user_id,
+        state = 'live' AND trash_expires_at IS NULL AS "is_visible!: bool"
+        FROM files
+        WHERE
+        (
+            state = 'live'
+            AND (
+                (downloads_remaining IS NOT NULL AND downloads_remaining < 1)
+                OR (expires_at IS NOT NULL AND expires_at < CURRENT_TIMESTAMP)
+                OR (trash_expires_at IS NOT NULL AND trash_expires_at < CURRENT_TIMESTAMP)
+            )
+        )
+        OR (state = 'pending' AND created_at < DATETIME('now', '-1 day'))
+        "#
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    report.expired_files = rows.iter().map(|r| r.uuid).collect();
+    if report.dry_run {
+        report.purged_files =
+            query!(r#"SELECT uuid AS "uuid: Uuid" FROM files WHERE state = 'tombstoned'"#)
+                .fetch_all(&mut *conn)
+                .await?
+                .iter()
+                .map(|r| r.uuid)
+                .chain(report.expired_files.iter().copied())
+                .collect();
         return Ok(());
     }
-
-    for entry in std::fs::read_dir(path)? {
-        delete_empty_directories(entry?.path().as_path())?;
+    for row in &rows {
+        file_state::tombstone(conn, row.uuid).await?;
+        // Deleted files and failed uploads have already gone from the user's list
+        if row.is_visible {
+            file_history::record(conn, row.user_id, row.uuid, FileAction::Expire, None).await?;
+            events.publish(row.user_id, FileEvent::Expired { uuid: row.uuid });
+        }
     }
+    report.purged_files = file_state::purge_tombstones(conn, store, None).await?;
+    Ok(())
+}
 
-    if std::fs::read_dir(path)?.next().is_none() {
-        std::fs::remove_dir(path)?;
-        println!("Deleted empty directory: {}", path.display());
+/// Remove resumable uploads that haven't received any data for a day
+async fn prune_staging(
+    conn: &mut SqliteConnection,
+    staging: &StagingArea,
+    report: &mut PruneReport,
+) -> anyhow::Result<()> {
+    report.expired_uploads = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid" FROM pending_uploads
+        WHERE updated_at < DATETIME('now', '-1 day')
+        "#
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .map(|r| r.uuid)
+    .collect();
+    if !report.dry_run {
+        for uuid in &report.expired_uploads {
+            query!("DELETE FROM pending_uploads WHERE uuid = ?1", uuid)
+                .execute(&mut *conn)
+                .await?;
+        }
     }
+    // As in `prune_blobs()`, uploads are created before their staging files, so list the files
+    // first
+    let listing = staging.list()?;
+    let live_uuids = query!(r#"SELECT uuid AS "uuid: Uuid" FROM pending_uploads"#)
+        .fetch_all(conn)
+        .await?
+        .iter()
+        .map(|r| r.uuid)
+        .filter(|uuid| !report.expired_uploads.contains(uuid))
+        .collect::<HashSet<Uuid>>();
 
+    for uuid in listing.blobs {
+        if live_uuids.contains(&uuid) {
+            continue;
+        }
+        if !report.dry_run {
+            // The upload may have finished since the listing, which moves the file away
+            match std::fs::remove_file(staging.path(uuid)?) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                result => result?,
+            }
+        }
+        report.orphan_uploads.push(uuid);
+    }
+    for stray in listing.stray {
+        if !report.dry_run {
+            staging.quarantine(&stray)?;
+        }
+        report.stray_files.push(stray);
+    }
     Ok(())
 }
 
-async fn prune_file_rows(conn: &mut SqliteConnection) -> sqlx::Result<()> {
-    query!(
+/// Remove bundles that are no longer wanted.
+///
+/// Files share their bundle's expiry time and download count, so they are tombstoned by
+/// `prune_file_rows()`.
+async fn prune_bundles(conn: &mut SqliteConnection, report: &mut PruneReport) -> sqlx::Result<()> {
+    let rows = query!(
         r#"
-        DELETE FROM files
-        WHERE
-        (salt IS NULL)
-        OR (downloads_remaining IS NOT NULL AND downloads_remaining < 1)
+        SELECT id, uuid AS "uuid: Uuid" FROM bundles
+        WHERE (downloads_remaining IS NOT NULL AND downloads_remaining < 1)
         OR (expires_at IS NOT NULL AND expires_at < CURRENT_TIMESTAMP)
+        OR (
+            created_at < DATETIME('now', '-1 day')
+            AND NOT EXISTS (
+                SELECT 1 FROM files WHERE bundle_id = bundles.id AND state != 'tombstoned'
+            )
+        )
+        "#
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for row in rows {
+        if !report.dry_run {
+            query!("DELETE FROM bundles WHERE id = ?1", row.id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        report.expired_bundles.push(row.uuid);
+    }
+    Ok(())
+}
+
+async fn prune_registration_tokens(
+    conn: &mut SqliteConnection,
+    report: &mut PruneReport,
+) -> sqlx::Result<()> {
+    let rows = query!(
+        r#"
+        SELECT registration_tokens.id, username
+        FROM registration_tokens JOIN users ON users.id = registration_tokens.user_id
+        WHERE registration_tokens.expires_at <= CURRENT_TIMESTAMP
         "#
     )
-    .execute(conn)
+    .fetch_all(&mut *conn)
     .await?;
+    for row in rows {
+        if !report.dry_run {
+            query!("DELETE FROM registration_tokens WHERE id = ?1", row.id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        report.expired_tokens.push(row.username);
+    }
+    Ok(())
+}
+
+/// Remove file history that's older than `retention` seconds.
+async fn prune_history(
+    conn: &mut SqliteConnection,
+    retention: u64,
+    report: &mut PruneReport,
+) -> sqlx::Result<()> {
+    let modifier = format!("-{} seconds", retention);
+    report.expired_history = if report.dry_run {
+        query!(
+            r#"SELECT COUNT(*) AS "count!: i64" FROM file_events WHERE created_at < DATETIME('now', ?1)"#,
+            modifier,
+        )
+        .fetch_one(conn)
+        .await?
+        .count as u64
+    } else {
+        query!(
+            "DELETE FROM file_events WHERE created_at < DATETIME('now', ?1)",
+            modifier,
+        )
+        .execute(conn)
+        .await?
+        .rows_affected()
+    };
     Ok(())
 }
 
-pub async fn prune(conn: &mut SqliteConnection, upload_root: &Path) -> anyhow::Result<()> {
-    prune_files(conn, upload_root).await?;
-    delete_empty_directories(upload_root)?;
-    prune_file_rows(conn).await?;
+/// Remove webhook deliveries that finished more than `retention` seconds ago.
+async fn prune_webhook_deliveries(
+    conn: &mut SqliteConnection,
+    retention: u64,
+    report: &mut PruneReport,
+) -> sqlx::Result<()> {
+    let modifier = format!("-{} seconds", retention);
+    report.expired_deliveries = if report.dry_run {
+        query!(
+            r#"
+            SELECT COUNT(*) AS "count!: i64" FROM webhook_deliveries
+            WHERE state != 'pending' AND updated_at < DATETIME('now', ?1)
+            "#,
+            modifier,
+        )
+        .fetch_one(conn)
+        .await?
+        .count as u64
+    } else {
+        query!(
+            r#"
+            DELETE FROM webhook_deliveries
+            WHERE state != 'pending' AND updated_at < DATETIME('now', ?1)
+            "#,
+            modifier,
+        )
+        .execute(conn)
+        .await?
+        .rows_affected()
+    };
+    Ok(())
+}
 
+/// Remove users that were created more than `grace` seconds ago, but never registered.
+async fn prune_unregistered_users(
+    conn: &mut SqliteConnection,
+    grace: u64,
+    report: &mut PruneReport,
+) -> sqlx::Result<()> {
+    let modifier = format!("-{} seconds", grace);
+    let rows = query!(
+        r#"
+        SELECT id, username FROM users
+        WHERE created_at < DATETIME('now', ?1)
+        AND NOT EXISTS (SELECT 1 FROM passkeys WHERE user_id = users.id)
+        AND NOT EXISTS (
+            SELECT 1 FROM registration_tokens
+            WHERE user_id = users.id AND expires_at > CURRENT_TIMESTAMP
+        )
+        "#,
+        modifier,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for row in rows {
+        if !report.dry_run {
+            query!("DELETE FROM users WHERE id = ?1", row.id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        report.unregistered_users.push(row.username);
+    }
     Ok(())
 }
+
+/// With `dry_run`, nothing is changed, but the report lists what would have been.
+pub async fn prune(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    staging: &StagingArea,
+    config: &PruneConfig,
+    events: &FileEvents,
+    dry_run: bool,
+) -> anyhow::Result<PruneReport> {
+    let mut report = PruneReport {
+        dry_run,
+        ..Default::default()
+    };
+    prune_file_rows(conn, store, events, &mut report).await?;
+    prune_bundles(conn, &mut report).await?;
+    prune_blobs(conn, store, &mut report).await?;
+    prune_staging(conn, staging, &mut report).await?;
+    prune_registration_tokens(conn, &mut report).await?;
+    prune_history(conn, config.history_retention, &mut report).await?;
+    prune_webhook_deliveries(conn, config.history_retention, &mut report).await?;
+    if let Some(grace) = config.unregistered_user_grace {
+        prune_unregistered_users(conn, grace, &mut report).await?;
+    }
+
+    Ok(report)
+}
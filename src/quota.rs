@@ -0,0 +1,196 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Per-user limits on stored files.
+//!
+//! Incomplete resumable uploads count towards the quota from when they're created, so a
+//! user can't exceed it with several uploads in parallel; files in the trash count until
+//! they're purged, as they're still stored.
+
+use crate::api_error::{ApiError, QuotaExceeded};
+use rocket::data::ByteUnit;
+use rocket::figment::Figment;
+use serde::Deserialize;
+use sqlx::{SqliteConnection, query};
+
+/// The `quotas` section of `Rocket.toml`; unset means unlimited.
+///
+/// These are defaults, which can be overridden for each user with the `set-quota` command.
+#[derive(Deserialize, Default)]
+pub struct QuotaConfig {
+    pub max_bytes: Option<ByteUnit>,
+    pub max_files: Option<i64>,
+}
+
+impl QuotaConfig {
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Self> {
+        if figment.contains("quotas") {
+            Ok(figment.extract_inner("quotas")?)
+        } else {
+            Ok(Self::default())
+        }
+    }
+}
+
+pub struct Usage {
+    pub bytes: i64,
+    pub files: i64,
+    pub max_bytes: Option<i64>,
+    pub max_files: Option<i64>,
+}
+
+impl Usage {
+    pub async fn for_user(
+        conn: &mut SqliteConnection,
+        config: &QuotaConfig,
+        user_id: i64,
+    ) -> Result<Self, ApiError> {
+        let row = query!(
+            r#"
+            SELECT
+            (
+                SELECT COALESCE(SUM(size), 0) FROM files
+                WHERE user_id = ?1
+                AND state != 'tombstoned'
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+                AND (downloads_remaining IS NULL or downloads_remaining > 0)
+            ) + (
+                SELECT COALESCE(SUM(upload_length), 0) FROM pending_uploads WHERE user_id = ?1
+            ) AS "bytes!: i64",
+            (
+                SELECT COUNT(*) FROM files
+                WHERE user_id = ?1
+                AND state != 'tombstoned'
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+                AND (downloads_remaining IS NULL or downloads_remaining > 0)
+            ) + (
+                SELECT COUNT(*) FROM pending_uploads WHERE user_id = ?1
+            ) AS "files!: i64",
+            max_bytes,
+            max_files
+            FROM users WHERE id = ?1
+            "#,
+            user_id,
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(Self {
+            bytes: row.bytes,
+            files: row.files,
+            max_bytes: row
+                .max_bytes
+                .or(config.max_bytes.map(|x| x.as_u64() as i64)),
+            max_files: row.max_files.or(config.max_files),
+        })
+    }
+
+    /// Check that a file of `size` bytes that has just been inserted, in the same transaction as
+    /// `conn`, fits in the user's quota.
+    ///
+    /// The insert takes the database's write lock, so concurrent uploads can't all fit in the
+    /// same space; the caller should roll back if this fails.
+    pub async fn check_inserted(
+        conn: &mut SqliteConnection,
+        config: &QuotaConfig,
+        user_id: i64,
+        size: u64,
+    ) -> Result<(), ApiError> {
+        let mut usage = Self::for_user(conn, config, user_id).await?;
+        usage.files -= 1;
+        usage.bytes -= size as i64;
+        usage.check(size)
+    }
+
+    /// Check that the user has room for another file of `size` bytes.
+    pub fn check(&self, size: u64) -> Result<(), ApiError> {
+        let size = size as i64;
+        if let Some(limit) = self.max_files
+            && self.files + 1 > limit
+        {
+            return Err(ApiError::QuotaExceeded(QuotaExceeded {
+                code: "quota_exceeded",
+                quota: "files",
+                limit,
+                used: self.files,
+                requested: 1,
+            }));
+        }
+        if let Some(limit) = self.max_bytes
+            && self.bytes + size > limit
+        {
+            return Err(ApiError::QuotaExceeded(QuotaExceeded {
+                code: "quota_exceeded",
+                quota: "bytes",
+                limit,
+                used: self.bytes,
+                requested: size,
+            }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_db::test_db;
+    use uuid::Uuid;
+
+    async fn insert_file(conn: &mut SqliteConnection, user_id: i64, size: i64) {
+        let uuid = Uuid::new_v4();
+        query!(
+            r#"
+            INSERT INTO files (user_id, uuid, metadata_iv, data_iv, encrypted_metadata, size)
+            VALUES (?1, ?2, '', '', '', ?3)
+            "#,
+            user_id,
+            uuid,
+            size,
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn check_inserted_counts_earlier_files() {
+        let mut conn = test_db().await;
+        let user_id = query!(
+            r#"
+            INSERT INTO users (username, uuid, max_files, max_bytes)
+            VALUES ('test', 'test', 2, 100)
+            RETURNING id
+            "#
+        )
+        .fetch_one(&mut conn)
+        .await
+        .unwrap()
+        .id;
+        let config = QuotaConfig::default();
+
+        insert_file(&mut conn, user_id, 60).await;
+        Usage::check_inserted(&mut conn, &config, user_id, 60)
+            .await
+            .unwrap();
+
+        // Fits the file count, but not the bytes
+        insert_file(&mut conn, user_id, 60).await;
+        match Usage::check_inserted(&mut conn, &config, user_id, 60).await {
+            Err(ApiError::QuotaExceeded(e)) => {
+                assert_eq!(e.quota, "bytes");
+                assert_eq!(e.used, 60);
+            }
+            _ => panic!("expected the byte quota to be exceeded"),
+        }
+
+        insert_file(&mut conn, user_id, 1).await;
+        match Usage::check_inserted(&mut conn, &config, user_id, 1).await {
+            Err(ApiError::QuotaExceeded(e)) => assert_eq!(e.quota, "files"),
+            _ => panic!("expected the file quota to be exceeded"),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::blob_store::{BlobReader, BlobStore};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::{Request, Response, response};
+use std::convert::Infallible;
+use std::io::Cursor;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// The conditional and range headers of a request.
+pub struct RangeRequest {
+    range: Option<String>,
+    if_range: Option<String>,
+    if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeRequest {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(Self {
+            range: headers.get_one("Range").map(str::to_string),
+            if_range: headers.get_one("If-Range").map(str::to_string),
+            if_none_match: headers.get_one("If-None-Match").map(str::to_string),
+        })
+    }
+}
+
+enum ByteRange {
+    Full,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a resource of `len` bytes.
+///
+/// Multi-range requests are answered with the full resource, which RFC 9110 permits.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last N bytes
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial {
+                start: len.saturating_sub(n),
+                end: len - 1,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    if end.is_empty() {
+        return ByteRange::Partial {
+            start,
+            end: len - 1,
+        };
+    }
+    match end.parse::<u64>() {
+        Ok(end) if end >= start => ByteRange::Partial {
+            start,
+            end: end.min(len - 1),
+        },
+        _ => ByteRange::Full,
+    }
+}
+
+/// A blob response supporting single-range `Range` requests, `ETag`, `If-Range`,
+/// and `If-None-Match`.
+pub struct RangedFile {
+    /// `None` if no body will be sent
+    blob: Option<BlobReader>,
+    len: u64,
+    etag: String,
+    range: ByteRange,
+    not_modified: bool,
+}
+
+impl RangedFile {
+    /// `etag` must change whenever the content changes; it is sent as a strong validator.
+    pub async fn open(
+        store: &dyn BlobStore,
+        uuid: Uuid,
+        etag: &str,
+        request: &RangeRequest,
+    ) -> std::io::Result<Self> {
+        let mut ret = Self::new(store.size(uuid).await?, etag, request);
+        ret.blob = match ret.range {
+            _ if ret.not_modified => None,
+            ByteRange::Full => Some(store.get(uuid, 0).await?),
+            ByteRange::Partial { start, .. } => Some(store.get(uuid, start).await?),
+            ByteRange::Unsatisfiable => None,
+        };
+        Ok(ret)
+    }
+
+    /// Like `open()`, for contents that are already in memory.
+    pub fn from_bytes(data: Vec<u8>, etag: &str, request: &RangeRequest) -> Self {
+        let mut ret = Self::new(data.len() as u64, etag, request);
+        let start = match ret.range {
+            _ if ret.not_modified => None,
+            ByteRange::Full => Some(0),
+            ByteRange::Partial { start, .. } => Some(start),
+            ByteRange::Unsatisfiable => None,
+        };
+        ret.blob = start.map(|start| {
+            let mut cursor = Cursor::new(data);
+            cursor.set_position(start);
+            Box::pin(cursor) as BlobReader
+        });
+        ret
+    }
+
+    /// Evaluate the request's headers; the caller provides the body.
+    fn new(len: u64, etag: &str, request: &RangeRequest) -> Self {
+        let etag = format!("\"{}\"", etag);
+
+        let not_modified = request
+            .if_none_match
+            .as_ref()
+            .is_some_and(|x| x.split(',').any(|tag| tag.trim() == etag));
+
+        let range = match &request.range {
+            // A stale If-Range validator means the client's partial copy is
+            // useless, so send the whole thing
+            Some(_) if request.if_range.as_ref().is_some_and(|x| x.trim() != etag) => {
+                ByteRange::Full
+            }
+            Some(range) => parse_range(range, len),
+            None => ByteRange::Full,
+        };
+
+        Self {
+            blob: None,
+            len,
+            etag,
+            range,
+            not_modified,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RangedFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.header(Header::new("ETag", self.etag));
+
+        if self.not_modified {
+            return response.status(Status::NotModified).ok();
+        }
+
+        response.header(Header::new("Accept-Ranges", "bytes"));
+        match (self.range, self.blob) {
+            (ByteRange::Full, Some(blob)) => response
+                .header(Header::new("Content-Length", self.len.to_string()))
+                .streamed_body(blob.take(self.len))
+                .ok(),
+            (ByteRange::Unsatisfiable, _) | (_, None) => response
+                .status(Status::RangeNotSatisfiable)
+                .header(Header::new(
+                    "Content-Range",
+                    format!("bytes */{}", self.len),
+                ))
+                .ok(),
+            (ByteRange::Partial { start, end }, Some(blob)) => {
+                let len = end - start + 1;
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, self.len),
+                    ))
+                    .header(Header::new("Content-Length", len.to_string()))
+                    .streamed_body(blob.take(len))
+                    .ok()
+            }
+        }
+    }
+}
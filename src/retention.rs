@@ -0,0 +1,200 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Limits on how long files are kept, and how many times they can be downloaded.
+
+use crate::api_error::ApiError;
+use rocket::figment::Figment;
+use serde::Deserialize;
+use sqlx::{SqliteConnection, query};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The `retention` section of `Rocket.toml`; unset means unlimited.
+///
+/// These are defaults, which can be overridden for each user with the `set-retention` command.
+#[derive(Deserialize, Default)]
+pub struct RetentionConfig {
+    /// Seconds until files expire, if the uploader doesn't say
+    pub default_expires_in: Option<i64>,
+    /// The furthest in the future that files can expire, in seconds
+    pub max_expires_in: Option<i64>,
+    /// Whether uploads must set `max_downloads`
+    #[serde(default)]
+    pub require_max_downloads: bool,
+    /// Seconds that deleted files can be restored for; unset means files are deleted
+    /// immediately
+    pub trash_period: Option<i64>,
+}
+
+impl RetentionConfig {
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Self> {
+        if !figment.contains("retention") {
+            return Ok(Self::default());
+        }
+        let config: Self = figment.extract_inner("retention")?;
+        if config.default_expires_in.is_some_and(|x| x < 1)
+            || config.max_expires_in.is_some_and(|x| x < 1)
+            || config.trash_period.is_some_and(|x| x < 1)
+        {
+            anyhow::bail!("retention durations must be at least 1 second");
+        }
+        Ok(config)
+    }
+}
+
+/// The policy for a specific user.
+pub struct RetentionPolicy {
+    pub default_expires_in: Option<i64>,
+    pub max_expires_in: Option<i64>,
+    pub require_max_downloads: bool,
+}
+
+impl RetentionPolicy {
+    pub async fn for_user(
+        conn: &mut SqliteConnection,
+        config: &RetentionConfig,
+        user_id: i64,
+    ) -> Result<Self, ApiError> {
+        let row = query!(
+            r#"
+            SELECT default_expires_in, max_expires_in, require_max_downloads AS "require_max_downloads: bool"
+            FROM users WHERE id = ?1
+            "#,
+            user_id,
+        )
+        .fetch_one(conn)
+        .await?;
+
+        let max_expires_in = row.max_expires_in.or(config.max_expires_in);
+        // Files that don't ask for an expiry time get the longest one allowed
+        let default_expires_in = match (
+            row.default_expires_in.or(config.default_expires_in),
+            max_expires_in,
+        ) {
+            (Some(default), Some(max)) => Some(default.min(max)),
+            (default, max) => default.or(max),
+        };
+        Ok(Self {
+            default_expires_in,
+            max_expires_in,
+            require_max_downloads: row
+                .require_max_downloads
+                .unwrap_or(config.require_max_downloads),
+        })
+    }
+
+    /// Check an upload's download limit and expiry time against the policy.
+    ///
+    /// Returns the expiry time to store, which may be the default.
+    pub fn apply(
+        &self,
+        max_downloads: Option<i32>,
+        expires_at: Option<i64>,
+    ) -> Result<Option<i64>, ApiError> {
+        match max_downloads {
+            Some(x) if x < 1 => {
+                return Err(ApiError::invalid_field(
+                    "max_downloads",
+                    "must be at least 1",
+                ));
+            }
+            None if self.require_max_downloads => {
+                return Err(ApiError::invalid_field("max_downloads", "is required"));
+            }
+            _ => (),
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        match expires_at {
+            Some(x) if x <= now => Err(ApiError::invalid_field("expires_at", "is in the past")),
+            Some(x) if self.max_expires_in.is_some_and(|max| x > now + max) => {
+                Err(ApiError::invalid_field(
+                    "expires_at",
+                    format!("must be within {} seconds", self.max_expires_in.unwrap()),
+                ))
+            }
+            Some(x) => Ok(Some(x)),
+            None => Ok(self.default_expires_in.map(|x| now + x)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn invalid_field(result: Result<Option<i64>, ApiError>) -> &'static str {
+        match result {
+            Err(ApiError::InvalidFieldError(e)) => e.field,
+            _ => panic!("expected an invalid field"),
+        }
+    }
+
+    const UNLIMITED: RetentionPolicy = RetentionPolicy {
+        default_expires_in: None,
+        max_expires_in: None,
+        require_max_downloads: false,
+    };
+
+    #[test]
+    fn unlimited() {
+        assert_eq!(UNLIMITED.apply(None, None).unwrap(), None);
+        let expires_at = now() + 10 * 365 * 24 * 60 * 60;
+        assert_eq!(
+            UNLIMITED.apply(Some(1), Some(expires_at)).unwrap(),
+            Some(expires_at)
+        );
+    }
+
+    #[test]
+    fn max_downloads() {
+        assert_eq!(
+            invalid_field(UNLIMITED.apply(Some(0), None)),
+            "max_downloads"
+        );
+        let policy = RetentionPolicy {
+            require_max_downloads: true,
+            ..UNLIMITED
+        };
+        assert_eq!(invalid_field(policy.apply(None, None)), "max_downloads");
+        assert!(policy.apply(Some(1), None).is_ok());
+    }
+
+    #[test]
+    fn expires_at() {
+        let policy = RetentionPolicy {
+            default_expires_in: Some(60),
+            max_expires_in: Some(3600),
+            require_max_downloads: false,
+        };
+        let before = now();
+        let default = policy.apply(None, None).unwrap().unwrap();
+        assert!((before + 60..=now() + 60).contains(&default));
+
+        let expires_at = now() + 1800;
+        assert_eq!(
+            policy.apply(None, Some(expires_at)).unwrap(),
+            Some(expires_at)
+        );
+        assert_eq!(
+            invalid_field(policy.apply(None, Some(now() + 7200))),
+            "expires_at"
+        );
+        assert_eq!(
+            invalid_field(policy.apply(None, Some(now() - 1))),
+            "expires_at"
+        );
+    }
+}
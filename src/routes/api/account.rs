@@ -0,0 +1,75 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::file_history;
+use crate::file_history::FileHistoryEntry;
+use crate::quota::{QuotaConfig, Usage};
+use crate::session::Session;
+use rocket::State;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use serde::Serialize;
+use ts_rs::TS;
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/account/UsageResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct UsageResponse {
+    /// Includes incomplete resumable uploads
+    #[ts(type = "number")]
+    pub used_bytes: i64,
+    #[ts(type = "number")]
+    pub used_files: i64,
+    /// `null` if unlimited
+    #[ts(type = "number | null")]
+    pub max_bytes: Option<i64>,
+    #[ts(type = "number | null")]
+    pub max_files: Option<i64>,
+}
+
+#[post("/api/account/usage")]
+pub async fn usage(
+    mut db: Connection<AppDb>,
+    session: Session,
+    quotas: &State<QuotaConfig>,
+) -> Result<Json<UsageResponse>, ApiError> {
+    let usage = Usage::for_user(&mut db, quotas, session.user_id()).await?;
+    Ok(Json(UsageResponse {
+        used_bytes: usage.bytes,
+        used_files: usage.files,
+        max_bytes: usage.max_bytes,
+        max_files: usage.max_files,
+    }))
+}
+
+/// Only the most recent entries are returned by `activity`.
+const MAX_ACTIVITY_ENTRIES: i64 = 500;
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/account/ActivityResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ActivityResponse {
+    /// Newest first
+    pub entries: Vec<FileHistoryEntry>,
+}
+
+/// Recent uploads, downloads, and deletions of all of the user's files.
+#[post("/api/account/activity")]
+pub async fn activity(
+    mut db: Connection<AppDb>,
+    session: Session,
+) -> Result<Json<ActivityResponse>, ApiError> {
+    let entries =
+        file_history::entries(&mut db, session.user_id(), None, Some(MAX_ACTIVITY_ENTRIES)).await?;
+    Ok(Json(ActivityResponse { entries }))
+}
+
+pub fn generate_typescript(dest: &str) {
+    ActivityResponse::export_all_to(dest).unwrap();
+    UsageResponse::export_all_to(dest).unwrap();
+}
@@ -0,0 +1,519 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Sets of files that share an expiry time and download count.
+//!
+//! Attaching a file copies the bundle's `expires_at` and `downloads_remaining` to it, and
+//! acknowledging a bundle download decrements both, so the rest of the server can treat
+//! bundled files like any other. Bundled files can only be downloaded through their bundle,
+//! and aren't included in `/api/files/list`.
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::blob_store::BlobStore;
+use crate::events::{FileEvent, FileEvents};
+use crate::file_history;
+use crate::file_history::{FileAction, RequestOrigin};
+use crate::file_state;
+use crate::ranged_file::{RangeRequest, RangedFile};
+use crate::retention::{RetentionConfig, RetentionPolicy};
+use crate::routes::api::files::{DownloadLeases, File, LeaseKind, file_from_row, open_contents};
+use crate::session::Session;
+use rocket::State;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::prelude::*;
+use rocket_db_pools::sqlx::query;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use ts_rs::TS;
+use uuid::Uuid;
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/bundles/Bundle.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct Bundle {
+    pub uuid: Uuid,
+    #[ts(type = "number")]
+    pub created_at: i64,
+    #[ts(type = "number | null")]
+    pub expires_at: Option<i64>,
+    #[ts(type = "number | null")]
+    pub downloads_remaining: Option<i64>,
+    pub files: Vec<File>,
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/bundles/CreateRequest.ts")]
+pub struct CreateRequest {
+    pub max_downloads: Option<i32>,
+    #[ts(type = "number | null")]
+    pub expires_at: Option<i64>,
+}
+
+/// Create an empty bundle; the retention policy is applied as it is for uploads.
+#[post("/api/bundles/create", data = "<payload>")]
+pub async fn create(
+    mut db: Connection<AppDb>,
+    payload: Json<CreateRequest>,
+    session: Session,
+    retention: &State<RetentionConfig>,
+) -> Result<Json<Bundle>, ApiError> {
+    let user_id = session.user_id();
+    let expires_at = RetentionPolicy::for_user(&mut db, retention, user_id)
+        .await?
+        .apply(payload.max_downloads, payload.expires_at)?;
+
+    let uuid = Uuid::new_v4();
+    let row = query!(
+        r#"
+        INSERT INTO bundles (uuid, user_id, downloads_remaining, expires_at)
+        VALUES (?1, ?2, ?3, DATETIME(?4, 'unixepoch'))
+        RETURNING created_at
+        "#,
+        uuid,
+        user_id,
+        payload.max_downloads,
+        expires_at,
+    )
+    .fetch_one(&mut **db)
+    .await?;
+
+    Ok(Json(Bundle {
+        uuid,
+        created_at: row.created_at.and_utc().timestamp(),
+        expires_at,
+        downloads_remaining: payload.max_downloads.map(i64::from),
+        files: vec![],
+    }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/bundles/AttachRequest.ts")]
+pub struct AttachRequest {
+    pub bundle: Uuid,
+    pub files: Vec<Uuid>,
+}
+
+/// Add existing files to a bundle, replacing their own expiry time and download limit.
+///
+/// Files must be live, not already in a bundle, and not being downloaded; clips can't be
+/// attached. Every file in a bundle must be for the same passkey, so that whoever downloads the
+/// bundle can decrypt all of it. If any file can't be attached, none are.
+#[post("/api/bundles/attach", data = "<payload>")]
+pub async fn attach(
+    mut db: Connection<AppDb>,
+    payload: Json<AttachRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let mut tx = db.begin().await?;
+    let bundle = query!(
+        r#"
+        SELECT id FROM bundles
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        "#,
+        payload.bundle,
+        user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    for file_uuid in &payload.files {
+        // Otherwise the lease would consume one of the file's own downloads, rather than the
+        // bundle's
+        if leases.is_leased(LeaseKind::File, *file_uuid) {
+            return Err(ApiError::ConflictError(format!(
+                "{} is being downloaded",
+                file_uuid
+            )));
+        }
+        let mismatched = query!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM files AS file, files AS other
+                WHERE file.uuid = ?2
+                AND other.bundle_id = ?1
+                AND other.state != 'tombstoned'
+                AND other.e2ee_passkey_id IS NOT file.e2ee_passkey_id
+            ) AS "mismatched!: bool"
+            "#,
+            bundle.id,
+            file_uuid,
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .mismatched;
+        if mismatched {
+            return Err(ApiError::invalid_field(
+                "files",
+                "must be for the same passkey as the bundle's other files",
+            ));
+        }
+
+        let result = query!(
+            r#"
+            UPDATE files SET
+            bundle_id = ?1,
+            expires_at = (SELECT expires_at FROM bundles WHERE id = ?1),
+            downloads_remaining = (SELECT downloads_remaining FROM bundles WHERE id = ?1)
+            WHERE uuid = ?2
+            AND user_id = ?3
+            AND state = 'live'
+            AND trash_expires_at IS NULL
+            AND bundle_id IS NULL
+            AND kind = 'file'
+            AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+            AND (downloads_remaining IS NULL or downloads_remaining > 0)
+            "#,
+            bundle.id,
+            file_uuid,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(ApiError::NotFoundError());
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/bundles/ListResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ListResponse {
+    bundles: Vec<Bundle>,
+}
+
+#[post("/api/bundles/list")]
+pub async fn list(
+    mut db: Connection<AppDb>,
+    session: Session,
+) -> Result<Json<ListResponse>, ApiError> {
+    let user_id = session.user_id();
+    let passkey_id = session.passkey_id();
+    let bundle_rows = query!(
+        r#"
+        SELECT id, uuid AS "uuid: Uuid", created_at, downloads_remaining,
+        CAST(STRFTIME('%s', expires_at) AS INTEGER) AS "expires_at: i64"
+        FROM bundles
+        WHERE user_id = ?1
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        ORDER BY created_at
+        "#,
+        user_id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+    let file_rows = query!(
+        r#"
+        SELECT bundle_id AS "bundle_id!", uuid as "uuid: Uuid", e2ee_passkey_id, salt, metadata_iv,
+        data_iv, encrypted_metadata, created_at, format_version,
+        (SELECT uuid FROM devices WHERE id = uploaded_by_device_id) AS "uploaded_by_device?: Uuid",
+        (SELECT uuid FROM devices WHERE id = target_device_id) AS "target_device?: Uuid"
+        FROM files
+        WHERE user_id = ?1
+        AND bundle_id IS NOT NULL
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND (e2ee_passkey_id IS NULL OR e2ee_passkey_id = ?2)
+        "#,
+        user_id,
+        passkey_id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+
+    let mut bundles: Vec<(i64, Bundle)> = bundle_rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.id,
+                Bundle {
+                    uuid: row.uuid,
+                    created_at: row.created_at.and_utc().timestamp(),
+                    expires_at: row.expires_at,
+                    downloads_remaining: row.downloads_remaining,
+                    files: vec![],
+                },
+            )
+        })
+        .collect();
+    for row in file_rows {
+        let Some((_, bundle)) = bundles.iter_mut().find(|(id, _)| *id == row.bundle_id) else {
+            continue;
+        };
+        bundle.files.push(file_from_row!(row));
+    }
+    Ok(Json(ListResponse {
+        bundles: bundles.into_iter().map(|(_, bundle)| bundle).collect(),
+    }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/bundles/DownloadRequest.ts")]
+pub struct DownloadRequest {
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/bundles/DownloadTicketFile.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct DownloadTicketFile {
+    pub uuid: Uuid,
+    /// Can be fetched without further authentication while the lease is live
+    pub url: String,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/bundles/DownloadTicket.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct DownloadTicket {
+    /// Pass to `download/ack` once every file has been downloaded
+    pub lease: Uuid,
+    pub files: Vec<DownloadTicketFile>,
+    pub is_final_download: bool,
+}
+
+/// Reserve one of the bundle's downloads, covering all of its files.
+#[post("/api/bundles/download/ticket", data = "<payload>")]
+pub async fn download_ticket(
+    mut db: Connection<AppDb>,
+    payload: Json<DownloadRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+) -> Result<Json<DownloadTicket>, ApiError> {
+    let user_id = session.user_id();
+    let bundle = query!(
+        r#"
+        SELECT id, downloads_remaining FROM bundles
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        "#,
+        payload.uuid,
+        user_id,
+    )
+    .fetch_one(&mut **db)
+    .await?;
+    let files = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid" FROM files
+        WHERE bundle_id = ?1 AND state = 'live' AND trash_expires_at IS NULL
+        "#,
+        bundle.id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+    if files.is_empty() {
+        return Err(ApiError::BadRequestError("Bundle is empty".to_string()));
+    }
+
+    let (lease, final_download) = leases
+        .reserve(
+            LeaseKind::Bundle,
+            payload.uuid,
+            user_id,
+            bundle.downloads_remaining,
+        )
+        .ok_or(ApiError::NotFoundError())?;
+    Ok(Json(DownloadTicket {
+        lease,
+        files: files
+            .into_iter()
+            .map(|row| DownloadTicketFile {
+                uuid: row.uuid,
+                url: uri!(download_by_ticket(lease, row.uuid)).to_string(),
+            })
+            .collect(),
+        is_final_download: final_download,
+    }))
+}
+
+#[get("/api/bundles/download/<lease>/<file>")]
+pub async fn download_by_ticket(
+    mut db: Connection<AppDb>,
+    lease: Uuid,
+    file: Uuid,
+    range: RangeRequest,
+    leases: &State<DownloadLeases>,
+    store: &State<Arc<dyn BlobStore>>,
+) -> Result<RangedFile, ApiError> {
+    let lease = leases
+        .get(lease, LeaseKind::Bundle)
+        .ok_or(ApiError::NotFoundError())?;
+    query!(
+        r#"
+        SELECT files.id
+        FROM files JOIN bundles ON bundles.id = files.bundle_id
+        WHERE bundles.uuid = ?1
+        AND bundles.user_id = ?2
+        AND files.uuid = ?3
+        AND files.state = 'live'
+        AND files.trash_expires_at IS NULL
+        AND (bundles.expires_at IS NULL OR bundles.expires_at > CURRENT_TIMESTAMP)
+        "#,
+        lease.target,
+        lease.user_id,
+        file,
+    )
+    .fetch_one(&mut **db)
+    .await?;
+
+    open_contents(&mut db, store.as_ref(), file, &range).await
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/bundles/DownloadAckRequest.ts")]
+pub struct DownloadAckRequest {
+    pub lease: Uuid,
+}
+
+/// Called by the client once every file in the bundle has been downloaded and decrypted.
+#[post("/api/bundles/download/ack", data = "<payload>")]
+pub async fn download_ack(
+    mut db: Connection<AppDb>,
+    payload: Json<DownloadAckRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let lease = leases
+        .remove(payload.lease, user_id, LeaseKind::Bundle)
+        .ok_or(ApiError::NotFoundError())?;
+
+    let mut tx = db.begin().await?;
+    let files = query!(
+        r#"
+        SELECT files.uuid AS "uuid: Uuid"
+        FROM files JOIN bundles ON bundles.id = files.bundle_id
+        WHERE bundles.uuid = ?1 AND bundles.user_id = ?2 AND files.state = 'live'
+        AND files.trash_expires_at IS NULL
+        "#,
+        lease.target,
+        user_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for file in &files {
+        file_history::record(
+            &mut tx,
+            user_id,
+            file.uuid,
+            FileAction::Download,
+            Some((&session, &origin)),
+        )
+        .await?;
+    }
+    // Bundles with unlimited downloads are left alone
+    if let Some(bundle) = query!(
+        r#"
+        UPDATE bundles SET downloads_remaining = downloads_remaining - 1
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND downloads_remaining > 0
+        RETURNING id
+        "#,
+        lease.target,
+        user_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        query!(
+            r#"
+            UPDATE files SET downloads_remaining = downloads_remaining - 1
+            WHERE bundle_id = ?1 AND downloads_remaining > 0
+            "#,
+            bundle.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    for file in files {
+        events.publish(user_id, FileEvent::Downloaded { uuid: file.uuid });
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/bundles/DeleteRequest.ts")]
+pub struct DeleteRequest {
+    pub uuid: Uuid,
+}
+
+/// Permanently delete a bundle and all of its files, including any that are in the trash.
+#[post("/api/bundles/delete", data = "<payload>")]
+pub async fn delete(
+    mut db: Connection<AppDb>,
+    payload: Json<DeleteRequest>,
+    session: Session,
+    store: &State<Arc<dyn BlobStore>>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let mut tx = db.begin().await?;
+    let bundle = query!(
+        "SELECT id FROM bundles WHERE uuid = ?1 AND user_id = ?2",
+        payload.uuid,
+        user_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let files = query!(
+        r#"
+        UPDATE files SET state = 'tombstoned' WHERE bundle_id = ?1 AND state != 'tombstoned'
+        RETURNING uuid AS "uuid: Uuid", trash_expires_at IS NULL AS "is_visible!: bool"
+        "#,
+        bundle.id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for file in &files {
+        file_history::record(
+            &mut tx,
+            user_id,
+            file.uuid,
+            FileAction::Delete,
+            Some((&session, &origin)),
+        )
+        .await?;
+    }
+    query!("DELETE FROM bundles WHERE id = ?1", bundle.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    // Files in the trash have already gone from the user's list
+    for file in files.iter().filter(|file| file.is_visible) {
+        events.publish(user_id, FileEvent::Deleted { uuid: file.uuid });
+    }
+
+    file_state::purge_tombstones(&mut db, store.as_ref(), Some(user_id)).await?;
+    Ok(())
+}
+
+pub fn generate_typescript(dest: &str) {
+    AttachRequest::export_all_to(dest).unwrap();
+    Bundle::export_all_to(dest).unwrap();
+    CreateRequest::export_all_to(dest).unwrap();
+    DeleteRequest::export_all_to(dest).unwrap();
+    DownloadAckRequest::export_all_to(dest).unwrap();
+    DownloadRequest::export_all_to(dest).unwrap();
+    DownloadTicket::export_all_to(dest).unwrap();
+    ListResponse::export_all_to(dest).unwrap();
+}
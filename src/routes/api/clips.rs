@@ -0,0 +1,289 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Short encrypted text, such as one-time passwords, public keys, or URLs.
+//!
+//! Clips are rows in `files` with `kind = 'clip'`; the ciphertext is stored in the row rather
+//! than the blob store. They use the version 1 format, so the text is a single AES-GCM
+//! ciphertext, and are deleted, trashed, and restored with the `/api/files` routes.
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::events::{FileEvent, FileEvents};
+use crate::file_format::{GCM_TAG_SIZE, LEGACY_FORMAT_VERSION, validate_crypto_params};
+use crate::file_history;
+use crate::file_history::{FileAction, RequestOrigin};
+use crate::quota::{QuotaConfig, Usage};
+use crate::retention::{RetentionConfig, RetentionPolicy};
+use crate::routes::api::devices::device_id;
+use crate::routes::api::files;
+use crate::routes::api::files::{
+    DownloadLeases, File, FileKind, LeaseKind, ListSort, is_uuid_used,
+};
+use crate::session::Session;
+use base64::prelude::*;
+use rocket::State;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::prelude::*;
+use rocket_db_pools::sqlx::query;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+use uuid::Uuid;
+
+/// The largest ciphertext that can be stored as a clip, in bytes.
+pub const MAX_CLIP_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/clips/CreateRequest.ts")]
+pub struct CreateRequest {
+    pub uuid: Uuid,
+    pub is_e2ee: bool,
+    pub salt: String,
+    pub metadata_iv: String,
+    pub data_iv: String,
+    pub encrypted_metadata: String,
+    /// Base64
+    pub encrypted_data: String,
+    pub max_downloads: Option<i32>,
+    #[ts(type = "number | null")]
+    pub expires_at: Option<i64>,
+    /// As for `/api/files/upload`
+    pub target_device: Option<Uuid>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/clips/CreateResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct CreateResponse {
+    pub clip: File,
+}
+
+#[post("/api/clips/create", data = "<payload>")]
+pub async fn create(
+    mut db: Connection<AppDb>,
+    payload: Json<CreateRequest>,
+    session: Session,
+    quotas: &State<QuotaConfig>,
+    retention: &State<RetentionConfig>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
+) -> Result<Json<CreateResponse>, ApiError> {
+    validate_crypto_params(
+        &payload.salt,
+        &payload.metadata_iv,
+        &payload.data_iv,
+        &payload.encrypted_metadata,
+    )?;
+    let data = BASE64_STANDARD
+        .decode(&payload.encrypted_data)
+        .map_err(|_| ApiError::invalid_field("encrypted_data", "not valid base64"))?;
+    if data.len() < GCM_TAG_SIZE as usize {
+        return Err(ApiError::invalid_field(
+            "encrypted_data",
+            "shorter than an AES-GCM tag",
+        ));
+    }
+    if data.len() > MAX_CLIP_SIZE {
+        return Err(ApiError::invalid_field(
+            "encrypted_data",
+            format!("must be at most {} bytes", MAX_CLIP_SIZE),
+        ));
+    }
+
+    let user_id = session.user_id();
+    let expires_at = RetentionPolicy::for_user(&mut db, retention, user_id)
+        .await?
+        .apply(payload.max_downloads, payload.expires_at)?;
+    if is_uuid_used(&mut db, payload.uuid).await? {
+        return Err(ApiError::BadRequestError("UUID already used".to_string()));
+    }
+    let target_device_id = match payload.target_device {
+        Some(uuid) => Some(device_id(&mut db, user_id, uuid).await?),
+        None => None,
+    };
+    let passkey_id = if payload.is_e2ee {
+        Some(session.passkey_id())
+    } else {
+        None
+    };
+    let device_id = session.device().map(|device| device.id);
+    let size = data.len() as i64;
+    // There's no blob to store, so the row can go straight to `live`
+    let mut tx = db.begin().await?;
+    let row = query!(
+        r#"
+    INSERT INTO files (uuid, user_id, e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata,
+    downloads_remaining, expires_at, format_version, size, kind, inline_data, uploaded_by_device_id,
+    target_device_id)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, DATETIME(?9, 'unixepoch'), ?10, ?11, 'clip', ?12,
+    (SELECT id FROM devices WHERE id = ?13), ?14)
+    RETURNING created_at
+        "#,
+        payload.uuid,
+        user_id,
+        passkey_id,
+        payload.salt,
+        payload.metadata_iv,
+        payload.data_iv,
+        payload.encrypted_metadata,
+        payload.max_downloads,
+        expires_at,
+        LEGACY_FORMAT_VERSION,
+        size,
+        data,
+        device_id,
+        target_device_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Usage::check_inserted(&mut tx, quotas, user_id, size as u64).await?;
+    tx.commit().await?;
+    file_history::record(
+        &mut db,
+        user_id,
+        payload.uuid,
+        FileAction::Upload,
+        Some((&session, &origin)),
+    )
+    .await?;
+    events.publish(user_id, FileEvent::Added { uuid: payload.uuid });
+
+    Ok(Json(CreateResponse {
+        clip: File {
+            uuid: payload.uuid,
+            is_e2ee: payload.is_e2ee,
+            salt: payload.salt.clone(),
+            metadata_iv: payload.metadata_iv.clone(),
+            data_iv: payload.data_iv.clone(),
+            encrypted_metadata: payload.encrypted_metadata.clone(),
+            created_at: row.created_at.and_utc().timestamp(),
+            format_version: LEGACY_FORMAT_VERSION,
+            uploaded_by_device: session.device().map(|device| device.uuid),
+            target_device: payload.target_device,
+        },
+    }))
+}
+
+/// Query parameters for `list`; all are optional, and behave as for `/api/files/list`.
+#[derive(TS, FromForm)]
+#[ts(export_to = "api/clips/ListQuery.ts")]
+pub struct ListQuery {
+    /// Defaults to `created`
+    pub sort: Option<ListSort>,
+    /// Defaults to 100, and can be at most 1000
+    #[ts(type = "number | null")]
+    pub limit: Option<u32>,
+    /// `next_cursor` from the previous page; the other parameters must not change
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/clips/ListResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ListResponse {
+    clips: Vec<File>,
+    /// Set if there are more clips; pass this as `cursor` to get them
+    next_cursor: Option<String>,
+}
+
+/// `/api/files/list` with `kind=clip`; the text itself is only returned by `read`.
+#[post("/api/clips/list?<query..>")]
+pub async fn list(
+    db: Connection<AppDb>,
+    session: Session,
+    query: ListQuery,
+) -> Result<Json<ListResponse>, ApiError> {
+    let page = files::list(
+        db,
+        session,
+        files::ListQuery {
+            for_this_device: None,
+            kind: Some(FileKind::Clip),
+            is_e2ee: None,
+            device: None,
+            bundle: None,
+            expiring_within: None,
+            sort: query.sort,
+            limit: query.limit,
+            cursor: query.cursor,
+        },
+    )
+    .await?
+    .into_inner();
+    Ok(Json(ListResponse {
+        clips: page.files,
+        next_cursor: page.next_cursor,
+    }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/clips/ReadRequest.ts")]
+pub struct ReadRequest {
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/clips/ReadResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ReadResponse {
+    /// Base64
+    pub encrypted_data: String,
+    /// Pass to `/api/files/download/ack` once the clip has been decrypted
+    pub lease: Uuid,
+    pub is_final_download: bool,
+}
+
+/// Reserves a download in the same way as `/api/files/download`.
+#[post("/api/clips/read", data = "<payload>")]
+pub async fn read(
+    mut db: Connection<AppDb>,
+    payload: Json<ReadRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+) -> Result<Json<ReadResponse>, ApiError> {
+    let user_id = session.user_id();
+    let row = query!(
+        r#"
+        SELECT downloads_remaining, inline_data AS "inline_data!"
+        FROM files
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND kind = 'clip'
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        "#,
+        payload.uuid,
+        user_id,
+    )
+    .fetch_one(&mut **db)
+    .await?;
+
+    let (lease, final_download) = leases
+        .reserve(
+            LeaseKind::File,
+            payload.uuid,
+            user_id,
+            row.downloads_remaining,
+        )
+        .ok_or(ApiError::NotFoundError())?;
+    Ok(Json(ReadResponse {
+        encrypted_data: BASE64_STANDARD.encode(row.inline_data),
+        lease,
+        is_final_download: final_download,
+    }))
+}
+
+pub fn generate_typescript(dest: &str) {
+    CreateRequest::export_all_to(dest).unwrap();
+    CreateResponse::export_all_to(dest).unwrap();
+    ListQuery::export_all_to(dest).unwrap();
+    ListResponse::export_all_to(dest).unwrap();
+    ReadRequest::export_all_to(dest).unwrap();
+    ReadResponse::export_all_to(dest).unwrap();
+}
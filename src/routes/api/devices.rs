@@ -0,0 +1,178 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Named devices, so that uploads can be sent to a specific computer.
+//!
+//! A device is created when a client names one while logging in; it can keep using it for later
+//! sessions by passing the same UUID.
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::session::{Session, SessionDevice};
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::query;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use ts_rs::TS;
+use uuid::Uuid;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Find the user's device with `uuid`, or create one called `name`.
+///
+/// If the device has been deleted, a new one is created, so that the client can carry on.
+/// Returns `None` if neither is given.
+pub async fn use_device(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    uuid: Option<Uuid>,
+    name: Option<&str>,
+) -> Result<Option<SessionDevice>, ApiError> {
+    let name = name.map(str::trim);
+    if name.is_some_and(|x| x.is_empty() || x.chars().count() > MAX_NAME_LENGTH) {
+        return Err(ApiError::invalid_field(
+            "device_name",
+            format!("must be 1 to {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+
+    if let Some(uuid) = uuid {
+        let row = query!(
+            r#"
+            UPDATE devices SET last_seen_at = CURRENT_TIMESTAMP, name = COALESCE(?3, name)
+            WHERE uuid = ?1 AND user_id = ?2
+            RETURNING id AS "id!"
+            "#,
+            uuid,
+            user_id,
+            name,
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(row) = row {
+            return Ok(Some(SessionDevice { id: row.id, uuid }));
+        }
+    }
+
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let uuid = Uuid::new_v4();
+    let id = query!(
+        "INSERT INTO devices (user_id, uuid, name) VALUES (?1, ?2, ?3)",
+        user_id,
+        uuid,
+        name,
+    )
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+    Ok(Some(SessionDevice { id, uuid }))
+}
+
+/// The ID of one of the user's devices, for `target_device` parameters.
+pub async fn device_id(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    uuid: Uuid,
+) -> Result<i64, ApiError> {
+    query!(
+        "SELECT id FROM devices WHERE uuid = ?1 AND user_id = ?2",
+        uuid,
+        user_id,
+    )
+    .fetch_optional(conn)
+    .await?
+    .map(|row| row.id)
+    .ok_or_else(|| ApiError::invalid_field("target_device", "no such device"))
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/devices/Device.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct Device {
+    pub uuid: Uuid,
+    pub name: String,
+    #[ts(type = "number")]
+    pub created_at: i64,
+    #[ts(type = "number")]
+    pub last_seen_at: i64,
+    /// Whether this is the device the current session is using
+    pub is_current: bool,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/devices/ListResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ListResponse {
+    devices: Vec<Device>,
+}
+
+#[post("/api/devices/list")]
+pub async fn list(
+    mut db: Connection<AppDb>,
+    session: Session,
+) -> Result<Json<ListResponse>, ApiError> {
+    let user_id = session.user_id();
+    let current = session.device().map(|device| device.id);
+    let rows = query!(
+        r#"
+        SELECT id, uuid AS "uuid: Uuid", name, created_at, last_seen_at
+        FROM devices
+        WHERE user_id = ?1
+        ORDER BY last_seen_at DESC
+        "#,
+        user_id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+    Ok(Json(ListResponse {
+        devices: rows
+            .into_iter()
+            .map(|row| Device {
+                uuid: row.uuid,
+                name: row.name,
+                created_at: row.created_at.and_utc().timestamp(),
+                last_seen_at: row.last_seen_at.and_utc().timestamp(),
+                is_current: current == Some(row.id),
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/devices/DeleteRequest.ts")]
+pub struct DeleteRequest {
+    pub uuid: Uuid,
+}
+
+/// Files sent to the device are kept, but are no longer targeted at any device.
+#[post("/api/devices/delete", data = "<payload>")]
+pub async fn delete(
+    mut db: Connection<AppDb>,
+    payload: Json<DeleteRequest>,
+    session: Session,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let result = query!(
+        "DELETE FROM devices WHERE uuid = ?1 AND user_id = ?2",
+        payload.uuid,
+        user_id,
+    )
+    .execute(&mut **db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFoundError());
+    }
+    Ok(())
+}
+
+pub fn generate_typescript(dest: &str) {
+    DeleteRequest::export_all_to(dest).unwrap();
+    Device::export_all_to(dest).unwrap();
+    ListResponse::export_all_to(dest).unwrap();
+}
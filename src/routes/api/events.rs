@@ -0,0 +1,63 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::events::{FileEvent, FileEvents};
+use crate::session::{Session, SessionStore};
+use rocket::response::stream::{Event, EventStream};
+use rocket::{Shutdown, State};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use ts_rs::TS;
+
+/// How often an open stream checks that its session hasn't expired.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Server-Sent Events for the session's user; each event's data is a `FileEvent`.
+///
+/// If the client falls behind, it's sent a `resync` event with no data, and should re-fetch
+/// the file list. The stream doesn't keep the session alive; once the session expires, it's
+/// sent a `session-expired` event with no data, and the stream ends.
+#[get("/api/events")]
+pub fn events<'a>(
+    session: Session,
+    events: &State<FileEvents>,
+    sessions: &'a State<SessionStore>,
+    mut shutdown: Shutdown,
+) -> EventStream![Event + 'a] {
+    let user_id = session.user_id();
+    let secret = session.secret().clone();
+    let mut receiver = events.subscribe();
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    EventStream! {
+        loop {
+            let notification = tokio::select! {
+                _ = session_check.tick() => {
+                    if !sessions.is_valid(&secret) {
+                        yield Event::empty().event("session-expired");
+                        break;
+                    }
+                    continue;
+                },
+                notification = receiver.recv() => match notification {
+                    Ok(notification) => notification,
+                    Err(RecvError::Lagged(_)) => {
+                        yield Event::empty().event("resync");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            if notification.user_id == user_id {
+                yield Event::json(&notification.event).event(notification.event.name());
+            }
+        }
+    }
+}
+
+pub fn generate_typescript(dest: &str) {
+    FileEvent::export_all_to(dest).unwrap();
+}
@@ -5,17 +5,36 @@
  */
 use crate::api_error::ApiError;
 use crate::app_db::AppDb;
+use crate::blob_store::{BlobStore, InlineThreshold};
+use crate::disk_space::HasDiskSpace;
+use crate::events::{FileEvent, FileEvents};
+use crate::file_format::{LEGACY_FORMAT_VERSION, validate_crypto_params, validate_framing};
+use crate::file_history;
+use crate::file_history::{FileAction, FileHistoryEntry, RequestOrigin};
+use crate::file_state;
+use crate::quota::{QuotaConfig, Usage};
+use crate::ranged_file::{RangeRequest, RangedFile};
+use crate::retention::{RetentionConfig, RetentionPolicy};
+use crate::routes::api::devices::device_id;
 use crate::session::Session;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use log::warn;
 use rocket::form::Form;
-use rocket::fs::{NamedFile, TempFile};
+use rocket::fs::TempFile;
 use rocket::http::Header;
 use rocket::serde::json::Json;
+use rocket::{Shutdown, State};
 use rocket_db_pools::Connection;
 use rocket_db_pools::sqlx::prelude::*;
 use rocket_db_pools::sqlx::query;
 use serde::{Deserialize, Serialize};
-use std::fs::exists;
+use sqlx::SqliteConnection;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use ts_rs::TS;
 use uuid::Uuid;
 
@@ -27,54 +46,236 @@ pub struct File {
     pub created_at: i64,
     pub is_e2ee: bool,
     pub salt: String,
-    pub filename_iv: String,
+    pub metadata_iv: String,
     pub data_iv: String,
-    pub encrypted_filename: String,
+    pub encrypted_metadata: String,
+    #[ts(type = "number")]
+    pub format_version: i64,
+    /// The device that uploaded the file, if the session had one
+    pub uploaded_by_device: Option<Uuid>,
+    /// The device the file was sent to; it expires once that device has downloaded it
+    pub target_device: Option<Uuid>,
+}
+
+/// Builds a `File` from a `files` row selected with the same columns as `list`.
+///
+/// Every `query!` has its own row type, so this is a macro rather than a function.
+macro_rules! file_from_row {
+    ($row:ident) => {
+        $crate::routes::api::files::File {
+            uuid: $row.uuid,
+            is_e2ee: $row.e2ee_passkey_id.is_some(),
+            salt: $row.salt.unwrap_or_default(),
+            metadata_iv: $row.metadata_iv,
+            data_iv: $row.data_iv,
+            encrypted_metadata: $row.encrypted_metadata,
+            created_at: $row.created_at.and_utc().timestamp(),
+            format_version: $row.format_version,
+            uploaded_by_device: $row.uploaded_by_device,
+            target_device: $row.target_device,
+        }
+    };
+}
+pub(crate) use file_from_row;
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(FromFormField, TS, Clone, Copy, Default)]
+#[ts(export_to = "api/files/FileKind.ts", rename_all = "lowercase")]
+pub enum FileKind {
+    #[default]
+    File,
+    Clip,
+}
+
+impl FileKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileKind::File => "file",
+            FileKind::Clip => "clip",
+        }
+    }
+}
+
+#[derive(FromFormField, TS, Clone, Copy, Default)]
+#[ts(export_to = "api/files/ListSort.ts", rename_all = "lowercase")]
+pub enum ListSort {
+    /// Newest first
+    #[default]
+    Created,
+    /// Soonest first; files that never expire are listed last
+    Expires,
+}
+
+/// Where the next page starts: the sort key and ID of the last file on the previous page.
+struct ListCursor {
+    key: i64,
+    id: i64,
+}
+
+impl ListCursor {
+    fn parse(cursor: &str) -> Result<Self, ApiError> {
+        let invalid = || ApiError::invalid_field("cursor", "not a valid cursor");
+        let decoded = BASE64_URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (key, id) = decoded.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            key: key.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(format!("{}.{}", self.key, self.id))
+    }
+}
+
+/// Query parameters for `list`; all are optional.
+#[derive(TS, FromForm)]
+#[ts(export_to = "api/files/ListQuery.ts")]
+pub struct ListQuery {
+    /// Only list files that were sent to the session's device
+    #[ts(type = "'true' | 'false' | null")]
+    pub for_this_device: Option<bool>,
+    /// Defaults to `file`
+    pub kind: Option<FileKind>,
+    #[ts(type = "'true' | 'false' | null")]
+    pub is_e2ee: Option<bool>,
+    /// Only list files uploaded by, or sent to, this device
+    #[ts(type = "string | null")]
+    pub device: Option<Uuid>,
+    /// List the files in this bundle, instead of files that aren't in a bundle
+    #[ts(type = "string | null")]
+    pub bundle: Option<Uuid>,
+    /// Only list files that expire within this many seconds
+    #[ts(type = "number | null")]
+    pub expiring_within: Option<u32>,
+    /// Defaults to `created`
+    pub sort: Option<ListSort>,
+    /// Defaults to 100, and can be at most 1000
+    #[ts(type = "number | null")]
+    pub limit: Option<u32>,
+    /// `next_cursor` from the previous page; the other parameters must not change
+    pub cursor: Option<String>,
 }
 
 #[derive(Serialize, TS)]
 #[ts(export_to = "api/files/ListResponse.ts")]
 #[serde(crate = "rocket::serde")]
 pub struct ListResponse {
-    files: Vec<File>,
+    pub files: Vec<File>,
+    /// Set if there are more files; pass this as `cursor` to get them
+    pub next_cursor: Option<String>,
 }
 
-#[post("/api/files/list")]
+#[post("/api/files/list?<query..>")]
 pub async fn list(
     mut db: Connection<AppDb>,
     session: Session,
+    query: ListQuery,
 ) -> Result<Json<ListResponse>, ApiError> {
     let user_id = session.user_id();
     let passkey_id = session.passkey_id();
-    let rows = query!(
+    let target_device_id = match query.for_this_device {
+        Some(true) => Some(
+            session
+                .device()
+                .ok_or_else(|| {
+                    ApiError::invalid_field("for_this_device", "the session has no device")
+                })?
+                .id,
+        ),
+        _ => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::invalid_field(
+            "limit",
+            format!("must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    let cursor = query.cursor.as_deref().map(ListCursor::parse).transpose()?;
+    let (cursor_key, cursor_id) = (cursor.as_ref().map(|x| x.key), cursor.map(|x| x.id));
+    let kind = query.kind.unwrap_or_default().as_str();
+    let by_expiry = matches!(query.sort.unwrap_or_default(), ListSort::Expires);
+    let expiring_within = query.expiring_within.map(|x| format!("+{} seconds", x));
+    // One more than the page size, to find out if there's another page
+    let fetch_limit = limit + 1;
+
+    // Files are sorted by `sort_key` then `sort_id`; IDs increase with `created_at`, so they're
+    // negated for newest-first.
+    let mut rows = query!(
         r#"
-    SELECT uuid as "uuid: Uuid", e2ee_passkey_id, salt, filename_iv, data_iv, encrypted_filename, created_at
-    FROM files
-    WHERE user_id = ?1
-    AND salt IS NOT NULL
-    AND (e2ee_passkey_id IS NULL OR e2ee_passkey_id = ?2)
-    AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
-    AND (downloads_remaining IS NULL or downloads_remaining > 0)
+    SELECT uuid as "uuid!: Uuid", e2ee_passkey_id, salt, metadata_iv as "metadata_iv!",
+    data_iv as "data_iv!", encrypted_metadata as "encrypted_metadata!", created_at as "created_at!",
+    format_version as "format_version!", uploaded_by_device as "uploaded_by_device?: Uuid",
+    target_device as "target_device?: Uuid", sort_key as "sort_key!: i64", sort_id as "sort_id!: i64"
+    FROM (
+        SELECT uuid, e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata, created_at,
+        format_version,
+        (SELECT uuid FROM devices WHERE id = uploaded_by_device_id) AS uploaded_by_device,
+        (SELECT uuid FROM devices WHERE id = target_device_id) AS target_device,
+        CASE WHEN ?4 THEN COALESCE(CAST(STRFTIME('%s', expires_at) AS INTEGER), 9223372036854775807)
+        ELSE 0 END AS sort_key,
+        CASE WHEN ?4 THEN id ELSE -id END AS sort_id
+        FROM files
+        WHERE user_id = ?1
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND kind = ?5
+        AND (e2ee_passkey_id IS NULL OR e2ee_passkey_id = ?2)
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        AND (?3 IS NULL OR target_device_id = ?3)
+        AND (?6 IS NULL OR (e2ee_passkey_id IS NOT NULL) = ?6)
+        AND (
+            ?7 IS NULL
+            OR uploaded_by_device_id = (SELECT id FROM devices WHERE uuid = ?7 AND user_id = ?1)
+            OR target_device_id = (SELECT id FROM devices WHERE uuid = ?7 AND user_id = ?1)
+        )
+        AND (
+            (?8 IS NULL AND bundle_id IS NULL)
+            OR bundle_id = (SELECT id FROM bundles WHERE uuid = ?8 AND user_id = ?1)
+        )
+        AND (?9 IS NULL OR expires_at <= DATETIME('now', ?9))
+    )
+    WHERE ?10 IS NULL OR (sort_key, sort_id) > (?10, ?11)
+    ORDER BY sort_key, sort_id
+    LIMIT ?12
     "#,
         user_id,
         passkey_id,
+        target_device_id,
+        by_expiry,
+        kind,
+        query.is_e2ee,
+        query.device,
+        query.bundle,
+        expiring_within,
+        cursor_key,
+        cursor_id,
+        fetch_limit,
     )
     .fetch_all(&mut **db)
     .await?;
 
-    let files: Vec<File> = rows
-        .into_iter()
-        .map(|row| File {
-            uuid: row.uuid,
-            is_e2ee: row.e2ee_passkey_id.is_some(),
-            salt: row.salt.unwrap(),
-            filename_iv: row.filename_iv,
-            data_iv: row.data_iv,
-            encrypted_filename: row.encrypted_filename,
-            created_at: row.created_at.and_utc().timestamp(),
+    let next_cursor = if rows.len() > limit as usize {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| {
+            ListCursor {
+                key: row.sort_key,
+                id: row.sort_id,
+            }
+            .encode()
         })
-        .collect();
-    Ok(Json(ListResponse { files }))
+    } else {
+        None
+    };
+    let files: Vec<File> = rows.into_iter().map(|row| file_from_row!(row)).collect();
+    Ok(Json(ListResponse { files, next_cursor }))
 }
 
 #[derive(TS, FromForm)]
@@ -84,14 +285,20 @@ pub struct UploadRequest<'r> {
     #[ts(type = "'true' | 'false'")]
     pub is_e2ee: bool,
     pub salt: String,
-    pub filename_iv: String,
+    pub metadata_iv: String,
     pub data_iv: String,
-    pub encrypted_filename: String,
+    pub encrypted_metadata: String,
     #[ts(type = "Blob")]
     pub encrypted_data: TempFile<'r>,
     pub max_downloads: Option<i32>,
     #[ts(type = "number | null")]
     pub expires_at: Option<i64>,
+    /// Defaults to `LEGACY_FORMAT_VERSION`
+    #[ts(type = "number | null")]
+    pub format_version: Option<i64>,
+    /// A device from `/api/devices/list`
+    #[ts(type = "string | null")]
+    pub target_device: Option<Uuid>,
 }
 
 #[derive(Serialize, TS)]
@@ -101,77 +308,272 @@ pub struct UploadResponse {
     pub file: File,
 }
 
-fn uploaded_file_path(uuid: &Uuid) -> Result<PathBuf, std::io::Error> {
-    let uuid_str = uuid.to_string();
-    let path = PathBuf::from(format!(
-        "uploads/{}/{}/{}",
-        &uuid_str[0..2],
-        &uuid_str[2..4],
-        uuid_str
-    ));
-    std::fs::create_dir_all(path.parent().unwrap())?;
-    Ok(path)
+/// Removes an upload's temporary file when dropped, so that it doesn't outlive a failed upload.
+///
+/// Removing it is a no-op once the blob store has moved it, or it's been read inline.
+struct TempUpload(PathBuf);
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Whether `uuid` belongs to an existing file or resumable upload, for any user.
+pub async fn is_uuid_used(db: &mut Connection<AppDb>, uuid: Uuid) -> Result<bool, ApiError> {
+    let row = query!(
+        r#"
+        SELECT
+        EXISTS(SELECT 1 FROM files WHERE uuid = ?1)
+        OR EXISTS(SELECT 1 FROM pending_uploads WHERE uuid = ?1)
+        AS "used!: bool"
+        "#,
+        uuid,
+    )
+    .fetch_one(&mut ***db)
+    .await?;
+    Ok(row.used)
 }
 
 #[post("/api/files/upload", data = "<payload>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload(
     mut db: Connection<AppDb>,
     mut payload: Form<UploadRequest<'_>>,
     session: Session,
+    _disk_space: HasDiskSpace,
+    store: &State<Arc<dyn BlobStore>>,
+    quotas: &State<QuotaConfig>,
+    retention: &State<RetentionConfig>,
+    inline_threshold: &State<InlineThreshold>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
 ) -> Result<Json<UploadResponse>, ApiError> {
-    let path = uploaded_file_path(&payload.uuid)?;
-    if exists(&path)? {
+    validate_crypto_params(
+        &payload.salt,
+        &payload.metadata_iv,
+        &payload.data_iv,
+        &payload.encrypted_metadata,
+    )?;
+    let user_id = session.user_id();
+    let expires_at = RetentionPolicy::for_user(&mut db, retention, user_id)
+        .await?
+        .apply(payload.max_downloads, payload.expires_at)?;
+
+    if is_uuid_used(&mut db, payload.uuid).await? {
         return Err(ApiError::BadRequestError("UUID already used".to_string()));
     }
+    let target_device_id = match payload.target_device {
+        Some(uuid) => Some(device_id(&mut db, user_id, uuid).await?),
+        None => None,
+    };
+    let size = payload.encrypted_data.len();
 
-    match payload.encrypted_data.persist_to(&path).await {
+    // Framing is validated locally, before the blob store sees it
+    let temp = TempUpload(std::env::temp_dir().join(format!("tempfiles-upload-{}", payload.uuid)));
+    let path = &temp.0;
+    match payload.encrypted_data.persist_to(path).await {
         Ok(_) => (),
         Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
-            payload.encrypted_data.move_copy_to(&path).await?;
+            payload.encrypted_data.move_copy_to(path).await?;
         }
         Err(e) => return Err(ApiError::IOError(e)),
     }
-    let user_id = session.user_id();
+    let format_version = payload.format_version.unwrap_or(LEGACY_FORMAT_VERSION);
+    validate_framing(path, format_version).await?;
+
     let passkey_id = if payload.is_e2ee {
         Some(session.passkey_id())
     } else {
         None
     };
+    // The device may have been deleted since the session started, so the insert checks for it
+    let device_id = session.device().map(|device| device.id);
+    let size_i64 = size as i64;
+    // There's no blob to store for inline files, so the row can go straight to `live`
+    let inline_data = if size <= inline_threshold.0 {
+        Some(tokio::fs::read(path).await?)
+    } else {
+        None
+    };
+    let state = if inline_data.is_some() {
+        "live"
+    } else {
+        "pending"
+    };
 
+    let mut tx = db.begin().await?;
     query!(
         r#"
-    INSERT INTO files (uuid, user_id, e2ee_passkey_id, salt, filename_iv, data_iv, encrypted_filename,
-    downloads_remaining, expires_at)
-    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, DATETIME(?9, 'unixepoch'))
+    INSERT INTO files (uuid, user_id, e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata,
+    downloads_remaining, expires_at, format_version, size, state, inline_data, uploaded_by_device_id,
+    target_device_id)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, DATETIME(?9, 'unixepoch'), ?10, ?11, ?12, ?13,
+    (SELECT id FROM devices WHERE id = ?14), ?15)
         "#,
         payload.uuid,
         user_id,
         passkey_id,
         payload.salt,
-        payload.filename_iv,
+        payload.metadata_iv,
         payload.data_iv,
-        payload.encrypted_filename,
+        payload.encrypted_metadata,
         payload.max_downloads,
-        payload.expires_at,
-    ).execute(&mut **db).await?;
+        expires_at,
+        format_version,
+        size_i64,
+        state,
+        inline_data,
+        device_id,
+        target_device_id,
+    ).execute(&mut *tx).await?;
+    Usage::check_inserted(&mut tx, quotas, user_id, size).await?;
+    tx.commit().await?;
+
+    if inline_data.is_none() {
+        if let Err(e) = store.put(payload.uuid, path).await {
+            file_state::tombstone(&mut db, payload.uuid).await?;
+            file_state::purge_or_defer(&mut db, store.as_ref(), payload.uuid).await;
+            return Err(e.into());
+        }
+        if !file_state::mark_live(&mut db, payload.uuid).await? {
+            return Err(ApiError::NotFoundError());
+        }
+    }
 
     let row = query!("SELECT created_at FROM files WHERE uuid = ?1", payload.uuid)
         .fetch_one(&mut **db)
         .await?;
+    file_history::record(
+        &mut db,
+        user_id,
+        payload.uuid,
+        FileAction::Upload,
+        Some((&session, &origin)),
+    )
+    .await?;
+    events.publish(user_id, FileEvent::Added { uuid: payload.uuid });
 
     Ok(Json(UploadResponse {
         file: File {
             uuid: payload.uuid,
             is_e2ee: payload.is_e2ee,
             salt: payload.salt.clone(),
-            filename_iv: payload.filename_iv.clone(),
+            metadata_iv: payload.metadata_iv.clone(),
             data_iv: payload.data_iv.clone(),
-            encrypted_filename: payload.encrypted_filename.clone(),
+            encrypted_metadata: payload.encrypted_metadata.clone(),
             created_at: row.created_at.and_utc().timestamp(),
+            format_version,
+            uploaded_by_device: session.device().map(|device| device.uuid),
+            target_device: payload.target_device,
         },
     }))
 }
 
+/// What a lease is for, so that it can only be used and acknowledged through the matching
+/// routes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaseKind {
+    /// A file or a clip
+    File,
+    Bundle,
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadLease {
+    pub kind: LeaseKind,
+    /// The file or bundle being downloaded
+    pub target: Uuid,
+    pub user_id: i64,
+    expires: Instant,
+}
+
+/// Downloads that have been sent but not yet acknowledged by the client.
+///
+/// Each lease reserves one of the file's or bundle's remaining downloads; the reservation
+/// is released if the lease expires without being acknowledged.
+#[derive(Debug, Default)]
+pub struct DownloadLeases {
+    data: Arc<Mutex<HashMap<Uuid, DownloadLease>>>,
+}
+
+impl DownloadLeases {
+    /// Reserve a download, unless all of `downloads_remaining` are already reserved.
+    ///
+    /// Returns the lease ID, and whether this lease holds the final download.
+    pub fn reserve(
+        &self,
+        kind: LeaseKind,
+        target: Uuid,
+        user_id: i64,
+        downloads_remaining: Option<i64>,
+    ) -> Option<(Uuid, bool)> {
+        let mut data = self.data.lock().unwrap();
+        let now = Instant::now();
+        data.retain(|_, lease| lease.expires > now);
+
+        let available = match downloads_remaining {
+            Some(remaining) => {
+                let reserved = data.values().filter(|lease| lease.target == target).count() as i64;
+                Some(remaining - reserved)
+            }
+            None => None,
+        };
+        if available.is_some_and(|x| x < 1) {
+            return None;
+        }
+
+        let uuid = Uuid::new_v4();
+        data.insert(
+            uuid,
+            DownloadLease {
+                kind,
+                target,
+                user_id,
+                expires: now + Duration::from_secs(10 * 60),
+            },
+        );
+        Some((uuid, available == Some(1)))
+    }
+
+    /// Look up a lease by ID alone, extending it as it is still in use.
+    pub fn get(&self, uuid: Uuid, kind: LeaseKind) -> Option<DownloadLease> {
+        match self.data.lock().unwrap().get_mut(&uuid) {
+            Some(lease) if lease.kind == kind && lease.expires > Instant::now() => {
+                lease.expires = Instant::now() + Duration::from_secs(10 * 60);
+                Some(lease.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether there's an unexpired lease for `target`.
+    pub fn is_leased(&self, kind: LeaseKind, target: Uuid) -> bool {
+        let now = Instant::now();
+        self.data
+            .lock()
+            .unwrap()
+            .values()
+            .any(|lease| lease.kind == kind && lease.target == target && lease.expires > now)
+    }
+
+    /// Leases of a different kind, or for another user, are left alone.
+    pub fn remove(&self, uuid: Uuid, user_id: i64, kind: LeaseKind) -> Option<DownloadLease> {
+        let mut data = self.data.lock().unwrap();
+        match data.get(&uuid) {
+            Some(v) if v.user_id != user_id || v.kind != kind => None,
+            _ => match data.remove(&uuid) {
+                Some(v) if v.expires > Instant::now() => Some(v),
+                _ => None,
+            },
+        }
+    }
+}
+
 #[derive(Deserialize, TS)]
 #[ts(export_to = "api/files/DownloadRequest.ts")]
 pub struct DownloadRequest {
@@ -180,84 +582,498 @@ pub struct DownloadRequest {
 
 #[derive(Responder)]
 pub struct DownloadResponse {
-    body: NamedFile,
+    body: RangedFile,
+    x_download_lease: Header<'static>,
     x_final_download: Header<'static>,
 }
 
 impl DownloadResponse {
-    pub fn new(body: NamedFile, final_download: bool) -> Self {
+    pub fn new(body: RangedFile, lease: Uuid, final_download: bool) -> Self {
         Self {
             body,
+            x_download_lease: Header::new("X-Download-Lease", lease.to_string()),
             x_final_download: Header::new("X-Final-Download", final_download.to_string()),
         }
     }
 }
 
+async fn reserve_download(
+    db: &mut Connection<AppDb>,
+    leases: &DownloadLeases,
+    file_uuid: Uuid,
+    user_id: i64,
+) -> Result<(Uuid, bool), ApiError> {
+    let row = query!(
+        r#"
+        SELECT downloads_remaining
+        FROM files
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND bundle_id IS NULL
+        AND kind = 'file'
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        "#,
+        file_uuid,
+        user_id,
+    )
+    .fetch_one(&mut ***db)
+    .await?;
+
+    leases
+        .reserve(LeaseKind::File, file_uuid, user_id, row.downloads_remaining)
+        .ok_or(ApiError::NotFoundError())
+}
+
+/// Open a file's contents, whether they're stored inline or in the blob store.
+pub async fn open_contents(
+    conn: &mut SqliteConnection,
+    store: &dyn BlobStore,
+    uuid: Uuid,
+    range: &RangeRequest,
+) -> Result<RangedFile, ApiError> {
+    let row = query!("SELECT inline_data FROM files WHERE uuid = ?1", uuid)
+        .fetch_one(conn)
+        .await?;
+    let etag = uuid.to_string();
+    Ok(match row.inline_data {
+        Some(data) => RangedFile::from_bytes(data, &etag, range),
+        None => RangedFile::open(store, uuid, &etag, range).await?,
+    })
+}
+
 #[post("/api/files/download", data = "<payload>")]
 pub async fn download(
     mut db: Connection<AppDb>,
     payload: Json<DownloadRequest>,
     session: Session,
+    range: RangeRequest,
+    leases: &State<DownloadLeases>,
+    store: &State<Arc<dyn BlobStore>>,
 ) -> Result<DownloadResponse, ApiError> {
     let user_id = session.user_id();
-    let row = query!(
+    let (lease, final_download) = reserve_download(&mut db, leases, payload.uuid, user_id).await?;
+
+    let file = match open_contents(&mut db, store.as_ref(), payload.uuid, &range).await {
+        Ok(file) => file,
+        Err(e) => {
+            leases.remove(lease, user_id, LeaseKind::File);
+            return Err(e);
+        }
+    };
+    Ok(DownloadResponse::new(file, lease, final_download))
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/files/DownloadTicket.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct DownloadTicket {
+    /// Pass to `download/ack` once the download is complete
+    pub lease: Uuid,
+    /// Can be fetched without further authentication while the lease is live
+    pub url: String,
+    pub is_final_download: bool,
+}
+
+/// Reserve a download that can be fetched - and resumed - with plain GET requests.
+#[post("/api/files/download/ticket", data = "<payload>")]
+pub async fn download_ticket(
+    mut db: Connection<AppDb>,
+    payload: Json<DownloadRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+) -> Result<Json<DownloadTicket>, ApiError> {
+    let (lease, final_download) =
+        reserve_download(&mut db, leases, payload.uuid, session.user_id()).await?;
+    Ok(Json(DownloadTicket {
+        lease,
+        url: uri!(download_by_ticket(lease)).to_string(),
+        is_final_download: final_download,
+    }))
+}
+
+#[get("/api/files/download/<lease>")]
+pub async fn download_by_ticket(
+    mut db: Connection<AppDb>,
+    lease: Uuid,
+    range: RangeRequest,
+    leases: &State<DownloadLeases>,
+    store: &State<Arc<dyn BlobStore>>,
+) -> Result<RangedFile, ApiError> {
+    let lease = leases
+        .get(lease, LeaseKind::File)
+        .ok_or(ApiError::NotFoundError())?;
+    query!(
         r#"
-        SELECT downloads_remaining
+        SELECT id
         FROM files
         WHERE uuid = ?1
         AND user_id = ?2
-        AND salt IS NOT NULL
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND bundle_id IS NULL
+        AND kind = 'file'
         AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
-        AND (downloads_remaining IS NULL or downloads_remaining > 0)
         "#,
-        payload.uuid,
-        user_id,
+        lease.target,
+        lease.user_id,
     )
     .fetch_one(&mut **db)
     .await?;
 
-    if row.downloads_remaining.is_some() {
-        query!(
-            "UPDATE files SET downloads_remaining = downloads_remaining - 1 WHERE uuid = ?1",
-            payload.uuid
+    open_contents(&mut db, store.as_ref(), lease.target, &range).await
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/DownloadAckRequest.ts")]
+pub struct DownloadAckRequest {
+    pub lease: Uuid,
+}
+
+/// Called by the client once a download has been successfully decrypted.
+#[post("/api/files/download/ack", data = "<payload>")]
+pub async fn download_ack(
+    mut db: Connection<AppDb>,
+    payload: Json<DownloadAckRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let lease = leases
+        .remove(payload.lease, user_id, LeaseKind::File)
+        .ok_or(ApiError::NotFoundError())?;
+
+    // Once the target device has the file, nobody else needs it
+    let device_id = session.device().map(|device| device.id);
+    let updated = query!(
+        r#"
+        UPDATE files SET downloads_remaining = CASE
+            WHEN target_device_id = ?3 THEN 0
+            ELSE downloads_remaining - 1
+        END
+        WHERE uuid = ?1
+        AND user_id = ?2
+        AND state = 'live'
+        AND bundle_id IS NULL
+        AND (downloads_remaining IS NULL OR downloads_remaining > 0 OR target_device_id = ?3)
+        "#,
+        lease.target,
+        user_id,
+        device_id,
+    )
+    .execute(&mut **db)
+    .await?
+    .rows_affected();
+    // The file was deleted, or attached to a bundle, while it was being downloaded
+    if updated != 1 {
+        return Ok(());
+    }
+    file_history::record(
+        &mut db,
+        user_id,
+        lease.target,
+        FileAction::Download,
+        Some((&session, &origin)),
+    )
+    .await?;
+    events.publish(user_id, FileEvent::Downloaded { uuid: lease.target });
+    Ok(())
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/DownloadReleaseRequest.ts")]
+pub struct DownloadReleaseRequest {
+    pub lease: Uuid,
+}
+
+/// Called by the client if a download couldn't be used, such as when decryption fails.
+///
+/// This gives back the download reserved by the lease, without waiting for it to expire.
+#[post("/api/files/download/release", data = "<payload>")]
+pub async fn download_release(
+    payload: Json<DownloadReleaseRequest>,
+    session: Session,
+    leases: &State<DownloadLeases>,
+) -> Result<(), ApiError> {
+    leases
+        .remove(payload.lease, session.user_id(), LeaseKind::File)
+        .ok_or(ApiError::NotFoundError())?;
+    Ok(())
+}
+
+/// The longest `/api/files/wait` will hold a request open, in seconds.
+const MAX_WAIT: u64 = 5 * 60;
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/WaitAfter.ts")]
+#[serde(untagged)]
+pub enum WaitAfter {
+    /// Unix timestamp
+    Timestamp(#[ts(type = "number")] i64),
+    /// A file or clip the client has already seen; it doesn't matter if it's since been deleted
+    Uuid(Uuid),
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/WaitRequest.ts")]
+pub struct WaitRequest {
+    /// Defaults to waiting for a file that hasn't been uploaded yet
+    pub after: Option<WaitAfter>,
+    /// Seconds; defaults to, and is limited to, `MAX_WAIT`
+    #[ts(type = "number | null")]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/files/WaitedFile.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct WaitedFile {
+    #[serde(flatten)]
+    pub file: File,
+    /// Clips are fetched with `/api/clips/read` rather than `/api/files/download`
+    #[ts(type = "'file' | 'clip'")]
+    pub kind: String,
+}
+
+#[derive(Responder)]
+pub enum WaitResponse {
+    File(Json<WaitedFile>),
+    #[response(status = 204)]
+    TimedOut(()),
+}
+
+/// The oldest file or clip that's visible to `list` and arrived after `after`.
+async fn next_file(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    passkey_id: i64,
+    after_id: Option<i64>,
+    after_timestamp: Option<i64>,
+) -> Result<Option<WaitedFile>, ApiError> {
+    let row = query!(
+        r#"
+    SELECT uuid as "uuid: Uuid", e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata, created_at,
+    format_version, kind,
+    (SELECT uuid FROM devices WHERE id = uploaded_by_device_id) AS "uploaded_by_device?: Uuid",
+    (SELECT uuid FROM devices WHERE id = target_device_id) AS "target_device?: Uuid"
+    FROM files
+    WHERE user_id = ?1
+    AND (?3 IS NULL OR id > ?3)
+    AND (?4 IS NULL OR created_at > DATETIME(?4, 'unixepoch'))
+    AND state = 'live'
+    AND trash_expires_at IS NULL
+    AND bundle_id IS NULL
+    AND (e2ee_passkey_id IS NULL OR e2ee_passkey_id = ?2)
+    AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+    AND (downloads_remaining IS NULL or downloads_remaining > 0)
+    ORDER BY id
+    LIMIT 1
+    "#,
+        user_id,
+        passkey_id,
+        after_id,
+        after_timestamp,
+    )
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|row| WaitedFile {
+        file: file_from_row!(row),
+        kind: row.kind,
+    }))
+}
+
+/// The ID that files must be newer than to come after `uuid`.
+///
+/// If the file has been purged, this falls back to files created before its upload was
+/// recorded, or, failing that, to files that haven't been uploaded yet.
+async fn wait_position(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    uuid: Uuid,
+) -> Result<i64, ApiError> {
+    let row = query!(
+        r#"
+        SELECT CASE
+        WHEN file_id IS NOT NULL THEN file_id
+        WHEN uploaded_at IS NOT NULL THEN
+            COALESCE((SELECT MAX(id) FROM files WHERE created_at < uploaded_at), 0)
+        ELSE COALESCE((SELECT MAX(id) FROM files), 0)
+        END AS "id!: i64"
+        FROM (
+            SELECT
+            (SELECT id FROM files WHERE uuid = ?1 AND user_id = ?2) AS file_id,
+            (
+                SELECT MIN(created_at) FROM file_events
+                WHERE file_uuid = ?1 AND user_id = ?2 AND action = 'upload'
+            ) AS uploaded_at
         )
-        .execute(&mut **db)
-        .await?;
+        "#,
+        uuid,
+        user_id,
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(row.id)
+}
+
+/// Wait for a file or clip to arrive, for scripts; responds with `204 No Content` if none
+/// arrives before the timeout.
+///
+/// The database connection is only held while checking, not while waiting.
+#[post("/api/files/wait", data = "<payload>")]
+pub async fn wait(
+    db: &State<AppDb>,
+    payload: Json<WaitRequest>,
+    session: Session,
+    events: &State<FileEvents>,
+    mut shutdown: Shutdown,
+) -> Result<WaitResponse, ApiError> {
+    let user_id = session.user_id();
+    let passkey_id = session.passkey_id();
+    let timeout = Duration::from_secs(payload.timeout.unwrap_or(MAX_WAIT).min(MAX_WAIT));
+    let deadline = tokio::time::Instant::now() + timeout;
+    // Subscribe before the first check, so that nothing can arrive unnoticed in between
+    let mut receiver = events.subscribe();
+
+    let (after_id, after_timestamp) = {
+        let mut conn = db.acquire().await?;
+        match payload.after {
+            Some(WaitAfter::Timestamp(timestamp)) => (None, Some(timestamp)),
+            Some(WaitAfter::Uuid(uuid)) => {
+                (Some(wait_position(&mut conn, user_id, uuid).await?), None)
+            }
+            None => {
+                let row = query!("SELECT MAX(id) AS max_id FROM files")
+                    .fetch_one(&mut *conn)
+                    .await?;
+                (Some(row.max_id.unwrap_or(0)), None)
+            }
+        }
+    };
+
+    loop {
+        let mut conn = db.acquire().await?;
+        if let Some(file) =
+            next_file(&mut conn, user_id, passkey_id, after_id, after_timestamp).await?
+        {
+            return Ok(WaitResponse::File(Json(file)));
+        }
+        drop(conn);
+
+        // Only additions can produce a match; anything else is just a reason to wait again
+        loop {
+            tokio::select! {
+                notification = receiver.recv() => match notification {
+                    Ok(notification) if notification.user_id == user_id
+                        && matches!(notification.event, FileEvent::Added { .. }) => break,
+                    Ok(_) => continue,
+                    // Missed events might have included a match
+                    Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Ok(WaitResponse::TimedOut(()));
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline) => return Ok(WaitResponse::TimedOut(())),
+                _ = &mut shutdown => return Ok(WaitResponse::TimedOut(())),
+            }
+        }
     }
+}
 
-    let path = uploaded_file_path(&payload.uuid)?;
-    Ok(DownloadResponse::new(
-        NamedFile::open(path).await?,
-        row.downloads_remaining.map_or(false, |x| x == 1),
-    ))
+/// Move live files to the trash, if `retention.trash_period` is set.
+///
+/// Files that have expired or have no downloads left can't be restored, so they are never
+/// moved to the trash.
+async fn move_to_trash(
+    conn: &mut SqliteConnection,
+    retention: &RetentionConfig,
+    user_id: i64,
+    file_uuid: Option<Uuid>,
+) -> Result<u64, ApiError> {
+    let Some(trash_period) = retention.trash_period else {
+        return Ok(0);
+    };
+    let modifier = format!("+{} seconds", trash_period);
+    Ok(query!(
+        r#"
+        UPDATE files SET trash_expires_at = DATETIME('now', ?3)
+        WHERE user_id = ?1
+        AND (?2 IS NULL OR uuid = ?2)
+        AND state = 'live'
+        AND trash_expires_at IS NULL
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (downloads_remaining IS NULL or downloads_remaining > 0)
+        "#,
+        user_id,
+        file_uuid,
+        modifier,
+    )
+    .execute(conn)
+    .await?
+    .rows_affected())
 }
 
 #[post("/api/files/delete_all")]
-pub async fn delete_all(mut db: Connection<AppDb>, session: Session) -> Result<(), ApiError> {
+pub async fn delete_all(
+    mut db: Connection<AppDb>,
+    session: Session,
+    store: &State<Arc<dyn BlobStore>>,
+    retention: &State<RetentionConfig>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
+) -> Result<(), ApiError> {
     let mut tx = db.begin().await?;
     let user_id = session.user_id();
-    query!("UPDATE files SET salt = NULL WHERE user_id = ?1", user_id)
-        .execute(&mut *tx)
-        .await?;
-    let rows = query!(
-        r#"SELECT uuid AS "uuid: Uuid" FROM files WHERE user_id = ?1"#,
+    let deleted = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid" FROM files
+        WHERE user_id = ?1 AND state = 'live' AND trash_expires_at IS NULL
+        "#,
         user_id
     )
     .fetch_all(&mut *tx)
     .await?;
-    tx.commit().await?;
-
-    for row in rows {
-        std::fs::remove_file(uploaded_file_path(&row.uuid)?)?
-    }
-
+    move_to_trash(&mut tx, retention, user_id, None).await?;
+    // Anything already in the trash stays there until it expires or is restored
     query!(
-        "DELETE FROM files WHERE user_id = ?1 AND salt IS NULL",
+        r#"
+        UPDATE files SET state = 'tombstoned'
+        WHERE user_id = ?1 AND state = 'live' AND trash_expires_at IS NULL
+        "#,
         user_id
     )
-    .execute(&mut **db)
+    .execute(&mut *tx)
+    .await?;
+    // Bundles with files in the trash are kept, so that restored files go back to their bundle
+    query!(
+        r#"
+        DELETE FROM bundles WHERE user_id = ?1
+        AND NOT EXISTS (SELECT 1 FROM files WHERE bundle_id = bundles.id AND state != 'tombstoned')
+        "#,
+        user_id
+    )
+    .execute(&mut *tx)
     .await?;
+    for row in &deleted {
+        file_history::record(
+            &mut tx,
+            user_id,
+            row.uuid,
+            FileAction::Delete,
+            Some((&session, &origin)),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    for row in deleted {
+        events.publish(user_id, FileEvent::Deleted { uuid: row.uuid });
+    }
 
+    file_state::purge_tombstones(&mut db, store.as_ref(), Some(user_id)).await?;
     Ok(())
 }
 
@@ -267,16 +1083,37 @@ pub struct DeleteRequest {
     pub uuid: Uuid,
 }
 
+/// Moves the file to the trash if enabled; deleting a file that's already in the trash
+/// removes it permanently.
 #[post("/api/files/delete", data = "<payload>")]
 pub async fn delete(
     mut db: Connection<AppDb>,
     payload: Json<DeleteRequest>,
     session: Session,
+    store: &State<Arc<dyn BlobStore>>,
+    retention: &State<RetentionConfig>,
+    events: &State<FileEvents>,
+    origin: RequestOrigin,
 ) -> Result<(), ApiError> {
     let user_id = session.user_id();
     let file_uuid = payload.uuid;
+    if move_to_trash(&mut db, retention, user_id, Some(file_uuid)).await? > 0 {
+        file_history::record(
+            &mut db,
+            user_id,
+            file_uuid,
+            FileAction::Delete,
+            Some((&session, &origin)),
+        )
+        .await?;
+        events.publish(user_id, FileEvent::Deleted { uuid: file_uuid });
+        return Ok(());
+    }
     let result = query!(
-        "DELETE FROM files WHERE uuid = ?1 AND user_id = ?2",
+        r#"
+        UPDATE files SET state = 'tombstoned'
+        WHERE uuid = ?1 AND user_id = ?2 AND state = 'live'
+        "#,
         file_uuid,
         user_id,
     )
@@ -285,15 +1122,256 @@ pub async fn delete(
     if result.rows_affected() == 0 {
         return Err(ApiError::NotFoundError());
     }
-    std::fs::remove_file(uploaded_file_path(&file_uuid)?)?;
+    file_history::record(
+        &mut db,
+        user_id,
+        file_uuid,
+        FileAction::Delete,
+        Some((&session, &origin)),
+    )
+    .await?;
+    events.publish(user_id, FileEvent::Deleted { uuid: file_uuid });
+    // The file is already gone as far as the user is concerned
+    file_state::purge_or_defer(&mut db, store.as_ref(), file_uuid).await;
     Ok(())
 }
 
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/files/TrashedFile.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct TrashedFile {
+    #[serde(flatten)]
+    pub file: File,
+    /// After this, the file is permanently deleted
+    #[ts(type = "number")]
+    pub trash_expires_at: i64,
+    #[ts(type = "'file' | 'clip'")]
+    pub kind: String,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/files/TrashResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct TrashResponse {
+    files: Vec<TrashedFile>,
+}
+
+/// Deleted files that can still be restored.
+#[post("/api/files/trash")]
+pub async fn trash(
+    mut db: Connection<AppDb>,
+    session: Session,
+) -> Result<Json<TrashResponse>, ApiError> {
+    let user_id = session.user_id();
+    let passkey_id = session.passkey_id();
+    let rows = query!(
+        r#"
+    SELECT uuid as "uuid: Uuid", e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata, created_at,
+    format_version, trash_expires_at AS "trash_expires_at!", kind,
+    (SELECT uuid FROM devices WHERE id = uploaded_by_device_id) AS "uploaded_by_device?: Uuid",
+    (SELECT uuid FROM devices WHERE id = target_device_id) AS "target_device?: Uuid"
+    FROM files
+    WHERE user_id = ?1
+    AND state = 'live'
+    AND (e2ee_passkey_id IS NULL OR e2ee_passkey_id = ?2)
+    AND trash_expires_at > CURRENT_TIMESTAMP
+    "#,
+        user_id,
+        passkey_id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+
+    let files = rows
+        .into_iter()
+        .map(|row| TrashedFile {
+            file: file_from_row!(row),
+            trash_expires_at: row.trash_expires_at.and_utc().timestamp(),
+            kind: row.kind,
+        })
+        .collect();
+    Ok(Json(TrashResponse { files }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/RestoreRequest.ts")]
+pub struct RestoreRequest {
+    pub uuid: Uuid,
+}
+
+/// Move a file out of the trash; trashed files already count towards the quota.
+#[post("/api/files/restore", data = "<payload>")]
+pub async fn restore(
+    mut db: Connection<AppDb>,
+    payload: Json<RestoreRequest>,
+    session: Session,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let result = query!(
+        r#"
+        UPDATE files SET trash_expires_at = NULL
+        WHERE uuid = ?1 AND user_id = ?2 AND state = 'live'
+        AND trash_expires_at > CURRENT_TIMESTAMP
+        "#,
+        payload.uuid,
+        user_id,
+    )
+    .execute(&mut **db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFoundError());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/files/HistoryRequest.ts")]
+pub struct HistoryRequest {
+    pub uuid: Uuid,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/files/HistoryResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct HistoryResponse {
+    /// Newest first
+    entries: Vec<FileHistoryEntry>,
+}
+
+/// Also available after the file has been deleted, until `prune.history_retention` has passed.
+#[post("/api/files/history", data = "<payload>")]
+pub async fn history(
+    mut db: Connection<AppDb>,
+    payload: Json<HistoryRequest>,
+    session: Session,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let entries =
+        file_history::entries(&mut db, session.user_id(), Some(payload.uuid), None).await?;
+    if entries.is_empty() {
+        return Err(ApiError::NotFoundError());
+    }
+    Ok(Json(HistoryResponse { entries }))
+}
+
 pub fn generate_typescript(dest: &str) {
     DeleteRequest::export_all_to(dest).unwrap();
+    DownloadAckRequest::export_all_to(dest).unwrap();
+    DownloadReleaseRequest::export_all_to(dest).unwrap();
     DownloadRequest::export_all_to(dest).unwrap();
+    DownloadTicket::export_all_to(dest).unwrap();
     File::export_all_to(dest).unwrap();
+    HistoryRequest::export_all_to(dest).unwrap();
+    HistoryResponse::export_all_to(dest).unwrap();
+    ListQuery::export_all_to(dest).unwrap();
     ListResponse::export_all_to(dest).unwrap();
+    RestoreRequest::export_all_to(dest).unwrap();
+    TrashResponse::export_all_to(dest).unwrap();
     UploadRequest::export_all_to(dest).unwrap();
     UploadResponse::export_all_to(dest).unwrap();
+    WaitRequest::export_all_to(dest).unwrap();
+    WaitedFile::export_all_to(dest).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        for (key, id) in [(0, 1), (1700000000, -42), (i64::MAX, i64::MIN)] {
+            let cursor = ListCursor::parse(&ListCursor { key, id }.encode()).unwrap();
+            assert_eq!((cursor.key, cursor.id), (key, id));
+        }
+    }
+
+    #[test]
+    fn cursor_is_url_safe() {
+        let encoded = ListCursor { key: -1, id: -1 }.encode();
+        assert!(
+            encoded
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        );
+    }
+
+    #[test]
+    fn invalid_cursors() {
+        let encode = |s: &str| BASE64_URL_SAFE_NO_PAD.encode(s);
+        for cursor in [
+            "not base64!".to_string(),
+            encode("12"),
+            encode("a.1"),
+            encode("1.b"),
+            encode("1.2.3"),
+            BASE64_URL_SAFE_NO_PAD.encode([0xff, 0xfe]),
+        ] {
+            assert!(matches!(
+                ListCursor::parse(&cursor),
+                Err(ApiError::InvalidFieldError(e)) if e.field == "cursor"
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn wait_position_survives_purge() {
+        let mut conn = crate::app_db::test_db().await;
+        let user_id =
+            query!("INSERT INTO users (username, uuid) VALUES ('test', 'test') RETURNING id")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap()
+                .id;
+        let mut files = vec![];
+        for created_at in [
+            "2025-01-01 00:00:00",
+            "2025-01-01 00:00:10",
+            "2025-01-01 00:00:20",
+        ] {
+            let uuid = Uuid::new_v4();
+            let id = query!(
+                r#"
+                INSERT INTO files (user_id, uuid, metadata_iv, data_iv, encrypted_metadata, created_at)
+                VALUES (?1, ?2, '', '', '', ?3)
+                RETURNING id
+                "#,
+                user_id,
+                uuid,
+                created_at,
+            )
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .id;
+            files.push((uuid, id));
+        }
+        let [(first, first_id), (purged, _), (_, last_id)] = files[..] else {
+            unreachable!();
+        };
+        query!(
+            r#"
+            INSERT INTO file_events (user_id, file_uuid, action, created_at)
+            VALUES (?1, ?2, 'upload', '2025-01-01 00:00:11')
+            "#,
+            user_id,
+            purged,
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        query!("DELETE FROM files WHERE uuid = ?1", purged)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        let position = wait_position(&mut conn, user_id, first).await.unwrap();
+        assert_eq!(position, first_id);
+        // Only the files created before it are skipped
+        let position = wait_position(&mut conn, user_id, purged).await.unwrap();
+        assert_eq!(position, first_id);
+        // Nothing is known about it, so only new files are returned
+        let position = wait_position(&mut conn, user_id, Uuid::new_v4())
+            .await
+            .unwrap();
+        assert_eq!(position, last_id);
+    }
 }
@@ -0,0 +1,50 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::api_error::ApiError;
+use crate::disk_space::DiskSpace;
+use crate::prune::{PruneMetrics, PruneStatus};
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use std::sync::Arc;
+use ts_rs::TS;
+
+/// Does not require a session, so it can be used by monitoring.
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/HealthResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct HealthResponse {
+    /// False if local disk space is below `storage.min_free_space`
+    pub accepting_uploads: bool,
+    /// For the local volume with the least space available
+    #[ts(type = "number")]
+    pub available_bytes: u64,
+    #[ts(type = "number")]
+    pub total_bytes: u64,
+    #[ts(type = "number")]
+    pub min_free_bytes: u64,
+    pub prune: PruneStatus,
+}
+
+#[get("/api/health")]
+pub async fn health(
+    disk_space: &State<DiskSpace>,
+    prune_metrics: &State<Arc<PruneMetrics>>,
+) -> Result<Json<HealthResponse>, ApiError> {
+    let capacity = disk_space.capacity()?;
+    Ok(Json(HealthResponse {
+        accepting_uploads: capacity.available_bytes >= disk_space.min_free_space(),
+        available_bytes: capacity.available_bytes,
+        total_bytes: capacity.total_bytes,
+        min_free_bytes: disk_space.min_free_space(),
+        prune: prune_metrics.status(),
+    }))
+}
+
+pub fn generate_typescript(dest: &str) {
+    HealthResponse::export_all_to(dest).unwrap();
+}
@@ -7,6 +7,7 @@
 use crate::api_error::ApiError;
 use crate::app_db::AppDb;
 use crate::prf_seed::PrfSeed;
+use crate::routes::api::devices::use_device;
 use crate::session::{SessionSecret, SessionStore};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::prelude::*;
@@ -85,6 +86,10 @@ pub struct FinishRequest {
     challenge_uuid: Uuid,
     #[ts(type = "unknown")]
     credential: PublicKeyCredential,
+    /// A device from a previous `FinishResponse`
+    device_uuid: Option<Uuid>,
+    /// Creates a device if `device_uuid` isn't set or no longer exists, or renames it if it does
+    device_name: Option<String>,
 }
 
 #[derive(Serialize, TS)]
@@ -93,6 +98,8 @@ pub struct FinishRequest {
 pub struct FinishResponse {
     username: String,
     session: SessionSecret,
+    /// Set if the request named a device; pass this as `device_uuid` when logging in again
+    device: Option<Uuid>,
 }
 
 #[post("/api/login/finish", data = "<payload>")]
@@ -138,12 +145,20 @@ pub async fn finish(
         return Err(ApiError::NotFoundError());
     }
 
+    let device = use_device(
+        &mut db,
+        data.user_id,
+        payload.device_uuid,
+        payload.device_name.as_deref(),
+    )
+    .await?;
     Ok(Json(FinishResponse {
         username: data.username,
         session: sessions
-            .create(data.user_id, data.passkey_id)
+            .create(data.user_id, data.passkey_id, device)
             .secret()
             .clone(),
+        device: device.map(|device| device.uuid),
     }))
 }
 
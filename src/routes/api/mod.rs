@@ -4,12 +4,35 @@
  *
  */
 
+pub mod account;
+pub mod bundles;
+pub mod clips;
+pub mod devices;
+pub mod events;
 pub mod files;
+pub mod health;
 pub mod login;
 pub mod register;
+pub mod server_info;
+pub mod uploads;
+pub mod webhooks;
+
+use crate::api_error::{InsufficientStorage, InvalidField, QuotaExceeded};
+use ts_rs::TS;
 
 pub fn generate_typescript(dest: &str) {
+    InsufficientStorage::export_all_to(dest).unwrap();
+    InvalidField::export_all_to(dest).unwrap();
+    QuotaExceeded::export_all_to(dest).unwrap();
+    account::generate_typescript(dest);
+    bundles::generate_typescript(dest);
+    clips::generate_typescript(dest);
+    devices::generate_typescript(dest);
+    events::generate_typescript(dest);
     files::generate_typescript(dest);
+    health::generate_typescript(dest);
     login::generate_typescript(dest);
     register::generate_typescript(dest);
+    server_info::generate_typescript(dest);
+    webhooks::generate_typescript(dest);
 }
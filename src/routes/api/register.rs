@@ -85,7 +85,7 @@ pub async fn start(
         webauthn.start_passkey_registration(uuid, &user.username, &user.username, None)?;
 
     registrations.add(
-        uuid.clone(),
+        uuid,
         PendingRegistration {
             state: server_state,
             user_id: user.user_id,
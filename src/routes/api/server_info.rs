@@ -0,0 +1,56 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::retention::{RetentionConfig, RetentionPolicy};
+use crate::session::Session;
+use rocket::State;
+use rocket::data::Limits;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use serde::Serialize;
+use ts_rs::TS;
+
+/// The limits that apply to the current user's uploads.
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/ServerInfoResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ServerInfoResponse {
+    #[ts(type = "number")]
+    pub max_upload_bytes: u64,
+    /// Seconds; applied if `expires_at` is not set
+    #[ts(type = "number | null")]
+    pub default_expires_in: Option<i64>,
+    /// Seconds; `expires_at` must be no later than this from now
+    #[ts(type = "number | null")]
+    pub max_expires_in: Option<i64>,
+    pub require_max_downloads: bool,
+    /// Seconds that deleted files can be restored for, if enabled
+    #[ts(type = "number | null")]
+    pub trash_period: Option<i64>,
+}
+
+#[post("/api/server_info")]
+pub async fn server_info(
+    mut db: Connection<AppDb>,
+    session: Session,
+    limits: &Limits,
+    retention: &State<RetentionConfig>,
+) -> Result<Json<ServerInfoResponse>, ApiError> {
+    let policy = RetentionPolicy::for_user(&mut db, retention, session.user_id()).await?;
+    Ok(Json(ServerInfoResponse {
+        max_upload_bytes: limits.get("file").unwrap_or(Limits::FILE).as_u64(),
+        default_expires_in: policy.default_expires_in,
+        max_expires_in: policy.max_expires_in,
+        require_max_downloads: policy.require_max_downloads,
+        trash_period: retention.trash_period,
+    }))
+}
+
+pub fn generate_typescript(dest: &str) {
+    ServerInfoResponse::export_all_to(dest).unwrap();
+}
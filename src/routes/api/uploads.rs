@@ -0,0 +1,558 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Resumable uploads, implementing the tus 1.0 core protocol and the `creation` extension.
+//!
+//! Partial uploads are kept in `pending_uploads` and the `StagingArea`; they're moved to `files`
+//! and the blob store once the final byte arrives.
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::blob_store::{BlobStore, InlineThreshold, StagingArea};
+use crate::disk_space::HasDiskSpace;
+use crate::events::{FileEvent, FileEvents};
+use crate::file_format::{
+    LEGACY_FORMAT_VERSION, is_supported_format_version, validate_crypto_params, validate_framing,
+};
+use crate::file_history;
+use crate::file_history::{FileAction, RequestOrigin};
+use crate::file_state;
+use crate::quota::{QuotaConfig, Usage};
+use crate::retention::{RetentionConfig, RetentionPolicy};
+use crate::routes::api::devices::device_id;
+use crate::routes::api::files::is_uuid_used;
+use crate::session::Session;
+use base64::prelude::*;
+use rocket::data::{Limits, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request, State};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::prelude::*;
+use rocket_db_pools::sqlx::query;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Adds `Tus-Resumable` to every response from these routes, including errors, as tus requires;
+/// `OPTIONS` responses are the exception.
+pub fn tus_resumable_header() -> AdHoc {
+    AdHoc::on_response("Tus-Resumable header", |request, response| {
+        Box::pin(async move {
+            if request.method() != Method::Options
+                && request.uri().path().starts_with("/api/uploads")
+            {
+                response.set_header(Header::new("Tus-Resumable", TUS_VERSION));
+            }
+        })
+    })
+}
+
+/// The tus headers of a request; the `Tus-Resumable` version is checked here.
+pub struct TusRequest {
+    upload_offset: Option<u64>,
+    upload_length: Option<u64>,
+    upload_metadata: HashMap<String, String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusRequest {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        if headers.get_one("Tus-Resumable") != Some(TUS_VERSION) {
+            return Outcome::Error((
+                Status::PreconditionFailed,
+                ApiError::BadRequestError("Unsupported Tus-Resumable version".to_string()),
+            ));
+        }
+
+        let parse_u64 = |name: &str| headers.get_one(name).map(|v| v.parse::<u64>());
+        let (upload_offset, upload_length) =
+            match (parse_u64("Upload-Offset"), parse_u64("Upload-Length")) {
+                (Some(Err(_)), _) | (_, Some(Err(_))) => {
+                    return Outcome::Error((
+                        Status::BadRequest,
+                        ApiError::BadRequestError("Invalid upload offset or length".to_string()),
+                    ));
+                }
+                (offset, length) => (offset.map(Result::unwrap), length.map(Result::unwrap)),
+            };
+
+        // Comma-separated `key base64value` pairs; the value is optional
+        let mut upload_metadata = HashMap::new();
+        for pair in headers.get_one("Upload-Metadata").unwrap_or("").split(',') {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let Some(key) = parts.next().filter(|k| !k.is_empty()) else {
+                continue;
+            };
+            let value = match parts.next().map(|v| BASE64_STANDARD.decode(v.trim())) {
+                Some(Ok(v)) => String::from_utf8(v).ok(),
+                Some(Err(_)) => None,
+                None => Some(String::new()),
+            };
+            let Some(value) = value else {
+                return Outcome::Error((
+                    Status::BadRequest,
+                    ApiError::BadRequestError(format!("Invalid metadata value for '{}'", key)),
+                ));
+            };
+            upload_metadata.insert(key.to_string(), value);
+        }
+
+        Outcome::Success(Self {
+            upload_offset,
+            upload_length,
+            upload_metadata,
+        })
+    }
+}
+
+impl TusRequest {
+    fn metadata(&self, key: &str) -> Result<&str, ApiError> {
+        self.upload_metadata
+            .get(key)
+            .map(String::as_str)
+            .ok_or_else(|| ApiError::BadRequestError(format!("Missing metadata '{}'", key)))
+    }
+
+    fn optional_metadata<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, ApiError> {
+        match self.upload_metadata.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .parse::<T>()
+                .map(Some)
+                .map_err(|_| ApiError::BadRequestError(format!("Invalid metadata '{}'", key))),
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 204)]
+pub struct OptionsResponse {
+    body: (),
+    tus_version: Header<'static>,
+    tus_extension: Header<'static>,
+    tus_max_size: Header<'static>,
+}
+
+#[options("/api/uploads")]
+pub fn options(limits: &Limits) -> OptionsResponse {
+    OptionsResponse {
+        body: (),
+        tus_version: Header::new("Tus-Version", TUS_VERSION),
+        tus_extension: Header::new("Tus-Extension", "creation"),
+        tus_max_size: Header::new(
+            "Tus-Max-Size",
+            limits
+                .get("file")
+                .unwrap_or(Limits::FILE)
+                .as_u64()
+                .to_string(),
+        ),
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 201)]
+pub struct CreateResponse {
+    body: (),
+    location: Header<'static>,
+    upload_offset: Header<'static>,
+}
+
+#[post("/api/uploads")]
+#[allow(clippy::too_many_arguments)]
+pub async fn create(
+    mut db: Connection<AppDb>,
+    tus: TusRequest,
+    session: Session,
+    _disk_space: HasDiskSpace,
+    limits: &Limits,
+    store: &State<Arc<dyn BlobStore>>,
+    staging: &State<StagingArea>,
+    quotas: &State<QuotaConfig>,
+    retention: &State<RetentionConfig>,
+    events: &State<FileEvents>,
+    inline_threshold: &State<InlineThreshold>,
+    origin: RequestOrigin,
+) -> Result<CreateResponse, ApiError> {
+    let upload_length = tus
+        .upload_length
+        .ok_or_else(|| ApiError::BadRequestError("Upload-Length is required".to_string()))?;
+    if upload_length > limits.get("file").unwrap_or(Limits::FILE).as_u64() {
+        return Err(ApiError::BadRequestError("Upload is too large".to_string()));
+    }
+
+    let uuid = Uuid::parse_str(tus.metadata("uuid")?)
+        .map_err(|_| ApiError::BadRequestError("Invalid UUID".to_string()))?;
+    let is_e2ee: bool = tus.optional_metadata("is_e2ee")?.unwrap_or(false);
+    let salt = tus.metadata("salt")?;
+    let metadata_iv = tus.metadata("metadata_iv")?;
+    let data_iv = tus.metadata("data_iv")?;
+    let encrypted_metadata = tus.metadata("encrypted_metadata")?;
+    let max_downloads: Option<i32> = tus.optional_metadata("max_downloads")?;
+    let expires_at: Option<i64> = tus.optional_metadata("expires_at")?;
+    let target_device: Option<Uuid> = tus.optional_metadata("target_device")?;
+    let format_version: i64 = tus
+        .optional_metadata("format_version")?
+        .unwrap_or(LEGACY_FORMAT_VERSION);
+    if !is_supported_format_version(format_version) {
+        return Err(ApiError::invalid_field("format_version", "unsupported"));
+    }
+    validate_crypto_params(salt, metadata_iv, data_iv, encrypted_metadata)?;
+    let user_id = session.user_id();
+    let expires_at = RetentionPolicy::for_user(&mut db, retention, user_id)
+        .await?
+        .apply(max_downloads, expires_at)?;
+
+    if is_uuid_used(&mut db, uuid).await? {
+        return Err(ApiError::BadRequestError("UUID already used".to_string()));
+    }
+    let target_device_id = match target_device {
+        Some(uuid) => Some(device_id(&mut db, user_id, uuid).await?),
+        None => None,
+    };
+
+    let passkey_id = if is_e2ee {
+        Some(session.passkey_id())
+    } else {
+        None
+    };
+    let device_id = session.device().map(|device| device.id);
+    let upload_length_i64 = upload_length as i64;
+    let mut tx = db.begin().await?;
+    query!(
+        r#"
+    INSERT INTO pending_uploads (uuid, user_id, e2ee_passkey_id, salt, metadata_iv, data_iv,
+    encrypted_metadata, downloads_remaining, expires_at, upload_length, format_version,
+    uploaded_by_device_id, target_device_id)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, DATETIME(?9, 'unixepoch'), ?10, ?11,
+    (SELECT id FROM devices WHERE id = ?12), ?13)
+        "#,
+        uuid,
+        user_id,
+        passkey_id,
+        salt,
+        metadata_iv,
+        data_iv,
+        encrypted_metadata,
+        max_downloads,
+        expires_at,
+        upload_length_i64,
+        format_version,
+        device_id,
+        target_device_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Usage::check_inserted(&mut tx, quotas, user_id, upload_length).await?;
+    tx.commit().await?;
+    tokio::fs::File::create(staging.path(uuid)?).await?;
+
+    if upload_length == 0 {
+        let by = (&session, &origin);
+        let store = store.as_ref();
+        finish(&mut db, store, staging, events, inline_threshold, uuid, by).await?;
+    }
+
+    Ok(CreateResponse {
+        body: (),
+        location: Header::new("Location", uri!(patch(uuid)).to_string()),
+        upload_offset: Header::new("Upload-Offset", "0"),
+    })
+}
+
+struct PendingUpload {
+    upload_length: u64,
+    offset: u64,
+}
+
+async fn get_pending_upload(
+    db: &mut Connection<AppDb>,
+    staging: &StagingArea,
+    uuid: Uuid,
+    user_id: i64,
+) -> Result<PendingUpload, ApiError> {
+    let row = query!(
+        "SELECT upload_length FROM pending_uploads WHERE uuid = ?1 AND user_id = ?2",
+        uuid,
+        user_id,
+    )
+    .fetch_one(&mut ***db)
+    .await?;
+    let offset = tokio::fs::metadata(staging.path(uuid)?).await?.len();
+    Ok(PendingUpload {
+        upload_length: row.upload_length as u64,
+        offset,
+    })
+}
+
+/// Move a completed upload out of staging, making it visible to `list`.
+///
+/// Small uploads are stored inline, as with `/api/files/upload`; uploads that turn out to be
+/// malformed are discarded.
+async fn finish(
+    db: &mut Connection<AppDb>,
+    store: &dyn BlobStore,
+    staging: &StagingArea,
+    events: &FileEvents,
+    inline_threshold: &InlineThreshold,
+    uuid: Uuid,
+    by: (&Session, &RequestOrigin),
+) -> Result<(), ApiError> {
+    let staging_path = staging.path(uuid)?;
+    let row = query!(
+        "SELECT user_id, format_version, upload_length FROM pending_uploads WHERE uuid = ?1",
+        uuid
+    )
+    .fetch_one(&mut ***db)
+    .await?;
+    if let Err(e) = validate_framing(&staging_path, row.format_version).await {
+        query!("DELETE FROM pending_uploads WHERE uuid = ?1", uuid)
+            .execute(&mut ***db)
+            .await?;
+        tokio::fs::remove_file(&staging_path).await?;
+        return Err(e);
+    }
+
+    // There's no blob to store for inline files, so the row can go straight to `live`
+    let inline_data = if row.upload_length as u64 <= inline_threshold.0 {
+        Some(tokio::fs::read(&staging_path).await?)
+    } else {
+        None
+    };
+    let state = if inline_data.is_some() {
+        "live"
+    } else {
+        "pending"
+    };
+    let mut tx = db.begin().await?;
+    // If storing the blob failed last time, the row is still pending, and is reused
+    let inserted = query!(
+        r#"
+    INSERT INTO files (uuid, user_id, e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata,
+    downloads_remaining, expires_at, format_version, size, state, inline_data, uploaded_by_device_id,
+    target_device_id)
+    SELECT uuid, user_id, e2ee_passkey_id, salt, metadata_iv, data_iv, encrypted_metadata,
+    downloads_remaining, expires_at, format_version, upload_length, ?2, ?3,
+    uploaded_by_device_id, target_device_id
+    FROM pending_uploads WHERE uuid = ?1
+    ON CONFLICT (uuid) DO UPDATE SET state = excluded.state, inline_data = excluded.inline_data
+    WHERE files.state = 'pending'
+        "#,
+        uuid,
+        state,
+        inline_data,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    // `prune` got there first
+    if inserted != 1 {
+        return Err(ApiError::NotFoundError());
+    }
+    if inline_data.is_some() {
+        query!("DELETE FROM pending_uploads WHERE uuid = ?1", uuid)
+            .execute(&mut *tx)
+            .await?;
+        file_history::record(&mut tx, row.user_id, uuid, FileAction::Upload, Some(by)).await?;
+        tx.commit().await?;
+        // Anything left behind is an orphan upload, and is removed by `prune`
+        tokio::fs::remove_file(&staging_path).await.ok();
+        events.publish(row.user_id, FileEvent::Added { uuid });
+        return Ok(());
+    }
+    tx.commit().await?;
+
+    // If this fails, the upload is left in staging and its row stays pending; an empty PATCH at
+    // the final offset retries this, or `prune` removes both once they're abandoned.
+    store.put(uuid, &staging_path).await?;
+
+    let mut tx = db.begin().await?;
+    if !file_state::mark_live(&mut tx, uuid).await? {
+        return Err(ApiError::NotFoundError());
+    }
+    query!("DELETE FROM pending_uploads WHERE uuid = ?1", uuid)
+        .execute(&mut *tx)
+        .await?;
+    file_history::record(&mut tx, row.user_id, uuid, FileAction::Upload, Some(by)).await?;
+    tx.commit().await?;
+    events.publish(row.user_id, FileEvent::Added { uuid });
+    Ok(())
+}
+
+#[derive(Responder)]
+pub struct HeadResponse {
+    body: (),
+    upload_offset: Header<'static>,
+    upload_length: Header<'static>,
+    cache_control: Header<'static>,
+}
+
+#[head("/api/uploads/<uuid>")]
+pub async fn head(
+    mut db: Connection<AppDb>,
+    uuid: Uuid,
+    _tus: TusRequest,
+    session: Session,
+    staging: &State<StagingArea>,
+) -> Result<HeadResponse, ApiError> {
+    let upload = get_pending_upload(&mut db, staging, uuid, session.user_id()).await?;
+    Ok(HeadResponse {
+        body: (),
+        upload_offset: Header::new("Upload-Offset", upload.offset.to_string()),
+        upload_length: Header::new("Upload-Length", upload.upload_length.to_string()),
+        cache_control: Header::new("Cache-Control", "no-store"),
+    })
+}
+
+/// Uploads with a `PATCH` in progress.
+///
+/// The offset is the length of the staging file, so two `PATCH`es for the same upload could
+/// otherwise both pass the offset check, then both append.
+#[derive(Debug, Default)]
+pub struct UploadLocks {
+    data: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+/// Unlocks the upload when dropped.
+pub struct UploadLock {
+    data: Arc<Mutex<HashSet<Uuid>>>,
+    uuid: Uuid,
+}
+
+impl UploadLocks {
+    /// Returns `None` if the upload is already locked.
+    pub fn try_lock(&self, uuid: Uuid) -> Option<UploadLock> {
+        if !self.data.lock().unwrap().insert(uuid) {
+            return None;
+        }
+        Some(UploadLock {
+            data: self.data.clone(),
+            uuid,
+        })
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.data.lock().unwrap().remove(&self.uuid);
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 204)]
+pub struct PatchResponse {
+    body: (),
+    upload_offset: Header<'static>,
+}
+
+#[patch(
+    "/api/uploads/<uuid>",
+    format = "application/offset+octet-stream",
+    data = "<data>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn patch(
+    mut db: Connection<AppDb>,
+    uuid: Uuid,
+    tus: TusRequest,
+    data: Data<'_>,
+    session: Session,
+    _disk_space: HasDiskSpace,
+    store: &State<Arc<dyn BlobStore>>,
+    staging: &State<StagingArea>,
+    events: &State<FileEvents>,
+    inline_threshold: &State<InlineThreshold>,
+    locks: &State<UploadLocks>,
+    origin: RequestOrigin,
+) -> Result<PatchResponse, ApiError> {
+    let _lock = locks.try_lock(uuid).ok_or_else(|| {
+        ApiError::ConflictError("Another PATCH for this upload is in progress".to_string())
+    })?;
+    let upload = get_pending_upload(&mut db, staging, uuid, session.user_id()).await?;
+    let offset = tus
+        .upload_offset
+        .ok_or_else(|| ApiError::BadRequestError("Upload-Offset is required".to_string()))?;
+    if offset != upload.offset {
+        return Err(ApiError::ConflictError(format!(
+            "Upload-Offset is {}, but the upload is at offset {}",
+            offset, upload.offset
+        )));
+    }
+
+    let path = staging.path(uuid)?;
+    let mut file = OpenOptions::new().append(true).open(&path).await?;
+    let remaining = upload.upload_length - upload.offset;
+    let result = data.open(remaining.bytes()).stream_to(&mut file).await;
+    file.flush().await?;
+    // If the connection drops, keep what we received; the client can HEAD then resume
+    let written = result?;
+    if !written.complete {
+        file.set_len(upload.offset).await?;
+        return Err(ApiError::BadRequestError(
+            "Upload exceeds Upload-Length".to_string(),
+        ));
+    }
+    drop(file);
+
+    query!(
+        "UPDATE pending_uploads SET updated_at = CURRENT_TIMESTAMP WHERE uuid = ?1",
+        uuid
+    )
+    .execute(&mut **db)
+    .await?;
+
+    let offset = upload.offset + written.written;
+    if offset == upload.upload_length {
+        let by = (&session, &origin);
+        let store = store.as_ref();
+        finish(&mut db, store, staging, events, inline_threshold, uuid, by).await?;
+    }
+
+    Ok(PatchResponse {
+        body: (),
+        upload_offset: Header::new("Upload-Offset", offset.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+
+    #[rocket::async_test]
+    async fn errors_have_tus_resumable_header() {
+        let rocket = rocket::build()
+            .attach(tus_resumable_header())
+            .mount("/", routes![options]);
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let response = client
+            .head(format!("/api/uploads/{}", Uuid::new_v4()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.headers().get_one("Tus-Resumable"),
+            Some(TUS_VERSION)
+        );
+
+        let response = client.options("/api/uploads").dispatch().await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Tus-Resumable"), None);
+
+        let response = client.get("/api/files/list").dispatch().await;
+        assert_eq!(response.headers().get_one("Tus-Resumable"), None);
+    }
+}
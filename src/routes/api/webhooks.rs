@@ -0,0 +1,301 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Managing webhooks, and checking how their deliveries went; see `crate::webhooks`.
+
+use crate::api_error::ApiError;
+use crate::app_db::AppDb;
+use crate::file_history::FileAction;
+use crate::session::Session;
+use crate::webhooks;
+use crate::webhooks::WebhookConfig;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use rocket::State;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::query;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use ts_rs::TS;
+use uuid::Uuid;
+
+const MAX_WEBHOOKS: i64 = 10;
+/// Only the most recent deliveries are returned by `deliveries`.
+const MAX_DELIVERIES: i64 = 100;
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/Webhook.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct Webhook {
+    pub uuid: Uuid,
+    pub url: String,
+    pub events: Vec<FileAction>,
+    #[ts(type = "number")]
+    pub created_at: i64,
+}
+
+async fn webhook_id(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    uuid: Uuid,
+) -> Result<i64, ApiError> {
+    query!(
+        "SELECT id FROM webhooks WHERE uuid = ?1 AND user_id = ?2",
+        uuid,
+        user_id,
+    )
+    .fetch_optional(conn)
+    .await?
+    .map(|row| row.id)
+    .ok_or(ApiError::NotFoundError())
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/webhooks/CreateRequest.ts")]
+pub struct CreateRequest {
+    /// `http` or `https`; internal addresses are rejected unless the server allows them
+    pub url: String,
+    pub events: Vec<FileAction>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/CreateResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct CreateResponse {
+    webhook: Webhook,
+    /// The key for `X-TempFiles-Signature`; this is only ever returned here
+    secret: String,
+}
+
+#[post("/api/webhooks/create", data = "<payload>")]
+pub async fn create(
+    mut db: Connection<AppDb>,
+    payload: Json<CreateRequest>,
+    session: Session,
+    config: &State<WebhookConfig>,
+) -> Result<Json<CreateResponse>, ApiError> {
+    let url = reqwest::Url::parse(&payload.url)
+        .map_err(|_| ApiError::invalid_field("url", "not a valid URL"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ApiError::invalid_field("url", "must be http or https"));
+    }
+    if !config.allow_internal_targets && webhooks::is_internal_url(&url) {
+        return Err(ApiError::invalid_field(
+            "url",
+            "must not be a loopback, private, or link-local address",
+        ));
+    }
+    if payload.events.is_empty() {
+        return Err(ApiError::invalid_field("events", "must not be empty"));
+    }
+
+    let user_id = session.user_id();
+    let count = query!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM webhooks WHERE user_id = ?1"#,
+        user_id,
+    )
+    .fetch_one(&mut **db)
+    .await?
+    .count;
+    if count >= MAX_WEBHOOKS {
+        return Err(ApiError::BadRequestError(format!(
+            "Users can have at most {} webhooks",
+            MAX_WEBHOOKS
+        )));
+    }
+
+    let uuid = Uuid::new_v4();
+    let url = url.to_string();
+    let events = serde_json::to_string(&payload.events).unwrap();
+    let secret = BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let row = query!(
+        r#"
+        INSERT INTO webhooks (user_id, uuid, url, events, secret) VALUES (?1, ?2, ?3, ?4, ?5)
+        RETURNING created_at
+        "#,
+        user_id,
+        uuid,
+        url,
+        events,
+        secret,
+    )
+    .fetch_one(&mut **db)
+    .await?;
+    Ok(Json(CreateResponse {
+        webhook: Webhook {
+            uuid,
+            url,
+            events: payload.into_inner().events,
+            created_at: row.created_at.and_utc().timestamp(),
+        },
+        secret,
+    }))
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/ListResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct ListResponse {
+    webhooks: Vec<Webhook>,
+}
+
+#[post("/api/webhooks/list")]
+pub async fn list(
+    mut db: Connection<AppDb>,
+    session: Session,
+) -> Result<Json<ListResponse>, ApiError> {
+    let user_id = session.user_id();
+    let rows = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid", url, events, created_at
+        FROM webhooks
+        WHERE user_id = ?1
+        ORDER BY created_at
+        "#,
+        user_id,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+    Ok(Json(ListResponse {
+        webhooks: rows
+            .into_iter()
+            .map(|row| Webhook {
+                uuid: row.uuid,
+                url: row.url,
+                events: serde_json::from_str(&row.events).unwrap_or_default(),
+                created_at: row.created_at.and_utc().timestamp(),
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Deserialize, TS)]
+#[ts(export_to = "api/webhooks/WebhookRequest.ts")]
+pub struct WebhookRequest {
+    pub uuid: Uuid,
+}
+
+/// Pending deliveries are discarded.
+#[post("/api/webhooks/delete", data = "<payload>")]
+pub async fn delete(
+    mut db: Connection<AppDb>,
+    payload: Json<WebhookRequest>,
+    session: Session,
+) -> Result<(), ApiError> {
+    let user_id = session.user_id();
+    let result = query!(
+        "DELETE FROM webhooks WHERE uuid = ?1 AND user_id = ?2",
+        payload.uuid,
+        user_id,
+    )
+    .execute(&mut **db)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFoundError());
+    }
+    Ok(())
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/TestResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct TestResponse {
+    delivery: Uuid,
+}
+
+/// Queue a `ping` delivery, whatever events the webhook is for.
+#[post("/api/webhooks/test", data = "<payload>")]
+pub async fn test(
+    mut db: Connection<AppDb>,
+    payload: Json<WebhookRequest>,
+    session: Session,
+) -> Result<Json<TestResponse>, ApiError> {
+    let id = webhook_id(&mut db, session.user_id(), payload.uuid).await?;
+    let delivery = webhooks::ping(&mut db, id).await?;
+    Ok(Json(TestResponse { delivery }))
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/Delivery.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct Delivery {
+    pub uuid: Uuid,
+    #[ts(type = "'upload' | 'download' | 'delete' | 'expire' | 'ping'")]
+    pub event: String,
+    /// `failed` once all attempts have been used
+    #[ts(type = "'pending' | 'delivered' | 'failed'")]
+    pub state: String,
+    #[ts(type = "number")]
+    pub attempts: i64,
+    /// The HTTP status of the last attempt, if it got a response
+    #[ts(type = "number | null")]
+    pub last_status: Option<i64>,
+    /// Why the last attempt didn't get a response
+    pub last_error: Option<String>,
+    #[ts(type = "number")]
+    pub created_at: i64,
+    #[ts(type = "number")]
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/DeliveriesResponse.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct DeliveriesResponse {
+    /// Newest first
+    deliveries: Vec<Delivery>,
+}
+
+#[post("/api/webhooks/deliveries", data = "<payload>")]
+pub async fn deliveries(
+    mut db: Connection<AppDb>,
+    payload: Json<WebhookRequest>,
+    session: Session,
+) -> Result<Json<DeliveriesResponse>, ApiError> {
+    let id = webhook_id(&mut db, session.user_id(), payload.uuid).await?;
+    let rows = query!(
+        r#"
+        SELECT uuid AS "uuid: Uuid", event, state, attempts, last_status, last_error, created_at,
+        updated_at
+        FROM webhook_deliveries
+        WHERE webhook_id = ?1
+        ORDER BY id DESC
+        LIMIT ?2
+        "#,
+        id,
+        MAX_DELIVERIES,
+    )
+    .fetch_all(&mut **db)
+    .await?;
+    Ok(Json(DeliveriesResponse {
+        deliveries: rows
+            .into_iter()
+            .map(|row| Delivery {
+                uuid: row.uuid,
+                event: row.event,
+                state: row.state,
+                attempts: row.attempts,
+                last_status: row.last_status,
+                last_error: row.last_error,
+                created_at: row.created_at.and_utc().timestamp(),
+                updated_at: row.updated_at.and_utc().timestamp(),
+            })
+            .collect(),
+    }))
+}
+
+pub fn generate_typescript(dest: &str) {
+    CreateRequest::export_all_to(dest).unwrap();
+    CreateResponse::export_all_to(dest).unwrap();
+    DeliveriesResponse::export_all_to(dest).unwrap();
+    Delivery::export_all_to(dest).unwrap();
+    ListResponse::export_all_to(dest).unwrap();
+    TestResponse::export_all_to(dest).unwrap();
+    Webhook::export_all_to(dest).unwrap();
+    WebhookRequest::export_all_to(dest).unwrap();
+    webhooks::WebhookPayload::export_all_to(dest).unwrap();
+}
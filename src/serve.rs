@@ -8,20 +8,29 @@ extern crate rocket;
 
 use crate::app_db::AppDb;
 use crate::app_html::{AppHtml, ViteConfig};
+use crate::blob_store::{BlobStore, StagingArea, StorageConfig};
+use crate::events::FileEvents;
 use crate::prf_seed::PrfSeed;
-use crate::prune::prune;
+use crate::prune::{PruneConfig, PruneMetrics, PruneReport, prune};
+use crate::quota::QuotaConfig;
+use crate::retention::RetentionConfig;
 use crate::routes::api;
+use crate::routes::api::files::DownloadLeases;
 use crate::routes::api::login::PendingLogins;
 use crate::routes::api::register::PendingRegistrations;
+use crate::routes::api::uploads::UploadLocks;
 use crate::session::SessionStore;
+use crate::webhooks;
+use crate::webhooks::{WebhookClient, WebhookConfig};
+use log::{error, info};
 use rocket::State;
 use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
 use rocket::response::content::RawHtml;
 use rocket_db_pools::Database;
 use serde::Deserialize;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio_util::sync::CancellationToken;
 use webauthn_rs::prelude::*;
 
@@ -47,21 +56,88 @@ struct RelyingParty {
     name: String,
 }
 
+async fn prune_once(
+    db: &AppDb,
+    store: &dyn BlobStore,
+    staging: &StagingArea,
+    config: &PruneConfig,
+    events: &FileEvents,
+) -> anyhow::Result<PruneReport> {
+    let mut conn = db.acquire().await?;
+    prune(&mut conn, store, staging, config, events, false).await
+}
+
+/// Failures are logged and retried, rather than stopping future runs.
 async fn prune_periodically(
     db: AppDb,
-    interval: Duration,
+    store: Arc<dyn BlobStore>,
+    staging: StagingArea,
+    config: PruneConfig,
+    metrics: Arc<PruneMetrics>,
+    events: FileEvents,
+    cancel: CancellationToken,
+) {
+    let mut delay = Duration::from_secs(rand::random_range(0..=config.jitter));
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = cancel.cancelled() => {
+                return;
+            }
+        }
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        let result = prune_once(&db, store.as_ref(), &staging, &config, &events).await;
+        metrics.record(started_at, start.elapsed(), &result);
+        delay = match result {
+            Ok(report) => {
+                // Most runs find nothing to do
+                let report = report.to_string();
+                if !report.is_empty() {
+                    info!("{}", report.trim_end());
+                }
+                Duration::from_secs(config.interval)
+            }
+            Err(e) => {
+                error!(
+                    "Prune failed, retrying in {} seconds: {}",
+                    config.retry_interval, e
+                );
+                Duration::from_secs(config.retry_interval)
+            }
+        };
+    }
+}
+
+/// How often to check for webhook deliveries that are due
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn deliver_webhooks_once(db: &AppDb, client: &WebhookClient) -> anyhow::Result<usize> {
+    let mut conn = db.acquire().await?;
+    Ok(webhooks::deliver_due(&mut conn, client).await?)
+}
+
+/// Deliveries are polled for rather than signalled, so that retries, and deliveries queued by
+/// `prune`, are picked up in the same way as everything else.
+async fn deliver_webhooks_periodically(
+    db: AppDb,
+    client: WebhookClient,
     cancel: CancellationToken,
-) -> anyhow::Result<()> {
-    let mut interval = tokio::time::interval(interval);
-    let uploads_root: PathBuf = PathBuf::from("uploads/");
+) {
     loop {
+        let delay = match deliver_webhooks_once(&db, &client).await {
+            // There may be more due
+            Ok(count) if count > 0 => Duration::ZERO,
+            Ok(_) => WEBHOOK_POLL_INTERVAL,
+            Err(e) => {
+                error!("Webhook delivery failed: {}", e);
+                WEBHOOK_POLL_INTERVAL
+            }
+        };
         tokio::select! {
-            _ = interval.tick() => {
-                let mut conn = db.acquire().await?;
-                prune(&mut conn, uploads_root.as_path()).await?
-            },
+            _ = tokio::time::sleep(delay) => {},
             _ = cancel.cancelled() => {
-                return Ok(());
+                return;
             }
         }
     }
@@ -80,50 +156,118 @@ async fn rocket_main() -> Result<(), rocket::Error> {
         .rp_name(&relying_party.name)
         .build()
         .expect("Failed to build webauthn");
+    let storage_config =
+        StorageConfig::from_figment(&config).expect("Invalid storage configuration");
+    let store = storage_config.open().expect("Failed to open storage");
+    let staging = storage_config.staging();
+    let disk_space = storage_config.disk_space();
+    let inline_threshold = storage_config.inline_threshold();
+    let quotas = QuotaConfig::from_figment(&config).expect("Invalid quota configuration");
+    let retention =
+        RetentionConfig::from_figment(&config).expect("Invalid retention configuration");
+    let prune_config = PruneConfig::from_figment(&config).expect("Invalid prune configuration");
+    let webhook_config =
+        WebhookConfig::from_figment(&config).expect("Invalid webhook configuration");
+    let webhook_client =
+        webhooks::client(&webhook_config).expect("Failed to create webhook client");
+    let prune_metrics = Arc::new(PruneMetrics::default());
+    let file_events = FileEvents::default();
 
     let background_tasks = CancellationToken::new();
     let background_tasks_stop_source = background_tasks.clone();
     let mut rocket = rocket::build()
         .configure(config)
         .attach(AppDb::init())
+        .attach(api::uploads::tus_resumable_header())
         .attach(AdHoc::on_shutdown("Cancel background tasks", move |_| {
             Box::pin(async move { background_tasks_stop_source.cancel() })
         }))
         .manage(AppHtml::init(&vite_config))
+        .manage(store.clone())
+        .manage(staging.clone())
+        .manage(disk_space)
+        .manage(inline_threshold)
+        .manage(DownloadLeases::default())
+        .manage(file_events.clone())
         .manage(PendingRegistrations::default())
         .manage(PendingLogins::default())
         .manage(PrfSeed::load_or_create())
+        .manage(prune_metrics.clone())
+        .manage(quotas)
+        .manage(retention)
         .manage(SessionStore::default())
+        .manage(UploadLocks::default())
         .manage(webauthn)
+        .manage(webhook_config)
         .mount(
             "/",
             routes![
                 root,
                 login,
                 register,
+                api::account::activity,
+                api::account::usage,
+                api::bundles::attach,
+                api::bundles::create,
+                api::bundles::delete,
+                api::bundles::download_ack,
+                api::bundles::download_by_ticket,
+                api::bundles::download_ticket,
+                api::bundles::list,
+                api::clips::create,
+                api::clips::list,
+                api::clips::read,
+                api::devices::delete,
+                api::devices::list,
+                api::events::events,
                 api::files::delete,
                 api::files::delete_all,
                 api::files::download,
+                api::files::download_ack,
+                api::files::download_by_ticket,
+                api::files::download_release,
+                api::files::download_ticket,
+                api::files::history,
                 api::files::list,
+                api::files::restore,
+                api::files::trash,
                 api::files::upload,
+                api::files::wait,
+                api::health::health,
                 api::register::start,
                 api::register::finish,
+                api::server_info::server_info,
                 api::login::start,
                 api::login::finish,
+                api::uploads::create,
+                api::uploads::head,
+                api::uploads::options,
+                api::uploads::patch,
+                api::webhooks::create,
+                api::webhooks::deliveries,
+                api::webhooks::delete,
+                api::webhooks::list,
+                api::webhooks::test,
             ],
         );
-    match vite_config {
-        ViteConfig::Release { root } => {
-            rocket = rocket.mount("/assets", FileServer::from(format!("{}/assets", root)));
-        }
-        _ => (),
+    if let ViteConfig::Release { root } = vite_config {
+        rocket = rocket.mount("/assets", FileServer::from(format!("{}/assets", root)));
     }
 
     let rocket = rocket.ignite().await?;
 
     tokio::spawn(prune_periodically(
         rocket.state::<AppDb>().unwrap().clone(),
-        Duration::from_secs(60 * 60),
+        store,
+        staging,
+        prune_config,
+        prune_metrics,
+        file_events,
+        background_tasks.clone(),
+    ));
+    tokio::spawn(deliver_webhooks_periodically(
+        rocket.state::<AppDb>().unwrap().clone(),
+        webhook_client,
         background_tasks.clone(),
     ));
     rocket.launch().await?;
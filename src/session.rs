@@ -15,6 +15,7 @@ use std::str::FromStr;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use ts_rs::TS;
+use uuid::Uuid;
 
 #[derive(TS, Debug, Clone, Hash, PartialEq, Eq)]
 #[ts(type = "string")]
@@ -41,7 +42,7 @@ impl Serialize for SessionSecret {
     where
         S: Serializer,
     {
-        let encoded = BASE64_URL_SAFE_NO_PAD.encode(&self.value);
+        let encoded = BASE64_URL_SAFE_NO_PAD.encode(self.value);
         serializer.serialize_str(&encoded)
     }
 }
@@ -79,9 +80,17 @@ pub struct Session {
     secret: SessionSecret,
     user_id: i64,
     passkey_id: i64,
+    device: Option<SessionDevice>,
     expires: Instant,
 }
 
+/// The device a session was started on, if the client named one when logging in.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionDevice {
+    pub id: i64,
+    pub uuid: Uuid,
+}
+
 impl Session {
     pub fn secret(&self) -> &SessionSecret {
         &self.secret
@@ -94,6 +103,10 @@ impl Session {
     pub fn passkey_id(&self) -> i64 {
         self.passkey_id
     }
+
+    pub fn device(&self) -> Option<SessionDevice> {
+        self.device
+    }
 }
 
 #[derive(Default)]
@@ -102,12 +115,13 @@ pub struct SessionStore {
 }
 
 impl SessionStore {
-    pub fn create(self: &Self, user_id: i64, passkey_id: i64) -> Session {
+    pub fn create(&self, user_id: i64, passkey_id: i64, device: Option<SessionDevice>) -> Session {
         let session = Session {
             expires: Instant::now() + Duration::from_secs(60 * 60),
             secret: SessionSecret::new(),
             user_id,
             passkey_id,
+            device,
         };
 
         self.sessions
@@ -117,7 +131,7 @@ impl SessionStore {
         session
     }
 
-    pub fn get<T: AsRef<SessionSecret>>(self: &Self, secret: T) -> Option<Session> {
+    pub fn get<T: AsRef<SessionSecret>>(&self, secret: T) -> Option<Session> {
         match self.sessions.lock().unwrap().get_mut(secret.as_ref()) {
             Some(session) if session.expires > Instant::now() => {
                 session.expires = Instant::now() + Duration::from_secs(60 * 60);
@@ -126,6 +140,15 @@ impl SessionStore {
             _ => None,
         }
     }
+
+    /// Unlike `get()`, this doesn't extend the session.
+    pub fn is_valid(&self, secret: &SessionSecret) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(secret)
+            .is_some_and(|session| session.expires > Instant::now())
+    }
 }
 
 #[rocket::async_trait]
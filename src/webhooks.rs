@@ -0,0 +1,584 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+//! Outbound webhooks, which tell other services when a user's files change.
+//!
+//! Deliveries are queued in the database alongside the file's history, then sent by
+//! `deliver_due()`. Payloads only contain metadata; filenames and keys never leave the client.
+
+use crate::file_history::FileAction;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{SqliteConnection, query};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ts_rs::TS;
+use uuid::Uuid;
+
+/// Deliveries are marked as `failed` after this many attempts
+const MAX_ATTEMPTS: i64 = 5;
+/// Seconds before the first retry; each later retry waits 4 times as long as the last
+const RETRY_DELAY: u64 = 60;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The most deliveries sent by each call to `deliver_due()`
+const BATCH_SIZE: i64 = 100;
+const CONCURRENCY: usize = 8;
+
+/// The `webhooks` section of `Rocket.toml`.
+#[derive(Deserialize, Default)]
+pub struct WebhookConfig {
+    /// Allow deliveries to loopback, private, and link-local addresses, such as `127.0.0.1` or
+    /// `192.168.0.1`; otherwise, users could use webhooks to reach services that are only meant
+    /// to be reachable from the server.
+    #[serde(default)]
+    pub allow_internal_targets: bool,
+}
+
+impl WebhookConfig {
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Self> {
+        if figment.contains("webhooks") {
+            Ok(figment.extract_inner("webhooks")?)
+        } else {
+            Ok(Self::default())
+        }
+    }
+}
+
+/// Whether `ip` is loopback, private, link-local, or otherwise not on the public internet.
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                // 0.0.0.0/8, which includes 'unspecified'
+                || a == 0
+                // 100.64.0.0/10, for carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_ip(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+/// Whether the URL's host is an internal IP address, or `localhost`.
+///
+/// Other hostnames are checked when they're resolved, by the resolver that `client()` uses.
+pub fn is_internal_url(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+    // IPv6 addresses are in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal_ip(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            host == "localhost" || host.ends_with(".localhost")
+        }
+    }
+}
+
+/// Resolves hostnames, but fails if they only resolve to internal addresses; the rest are
+/// dropped.
+///
+/// Checking the resolved addresses, rather than the hostname when the webhook is created, means
+/// a hostname can't be pointed somewhere internal later.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(
+                    format!("{} only resolves to internal addresses", name.as_str()).into(),
+                );
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The JSON body of each delivery.
+#[derive(Serialize, TS)]
+#[ts(export_to = "api/webhooks/WebhookPayload.ts")]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookPayload {
+    /// The delivery's UUID, which is the same for every attempt
+    pub id: Uuid,
+    #[ts(type = "'upload' | 'download' | 'delete' | 'expire' | 'ping'")]
+    pub event: &'static str,
+    /// Unset for `ping`
+    pub file: Option<Uuid>,
+    /// The device that took the action, if any
+    pub device: Option<Uuid>,
+    #[ts(type = "number")]
+    pub created_at: i64,
+}
+
+async fn insert(
+    conn: &mut SqliteConnection,
+    webhook_id: i64,
+    event: &'static str,
+    file: Option<Uuid>,
+    device: Option<Uuid>,
+) -> sqlx::Result<Uuid> {
+    let uuid = Uuid::new_v4();
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs() as i64);
+    let payload = serde_json::to_string(&WebhookPayload {
+        id: uuid,
+        event,
+        file,
+        device,
+        created_at,
+    })
+    .unwrap();
+    query!(
+        "INSERT INTO webhook_deliveries (webhook_id, uuid, event, payload) VALUES (?1, ?2, ?3, ?4)",
+        webhook_id,
+        uuid,
+        event,
+        payload,
+    )
+    .execute(conn)
+    .await?;
+    Ok(uuid)
+}
+
+/// Queue a delivery to each of the user's webhooks that want `action`.
+pub async fn enqueue(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    action: FileAction,
+    file_uuid: Uuid,
+    device: Option<Uuid>,
+) -> sqlx::Result<()> {
+    let event = action.as_str();
+    let webhooks = query!(
+        r#"
+        SELECT id FROM webhooks
+        WHERE user_id = ?1 AND EXISTS (SELECT 1 FROM json_each(webhooks.events) WHERE value = ?2)
+        "#,
+        user_id,
+        event,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for webhook in webhooks {
+        insert(conn, webhook.id, event, Some(file_uuid), device).await?;
+    }
+    Ok(())
+}
+
+/// Queue a `ping` delivery, so that users can check their receiver.
+pub async fn ping(conn: &mut SqliteConnection, webhook_id: i64) -> sqlx::Result<Uuid> {
+    insert(conn, webhook_id, "ping", None, None).await
+}
+
+/// The value of the `X-TempFiles-Signature` header: an HMAC-SHA256 of the body, keyed with the
+/// webhook's secret.
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    format!(
+        "sha256={}",
+        digest
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>()
+    )
+}
+
+/// Sends deliveries, following `WebhookConfig`.
+pub struct WebhookClient {
+    http: reqwest::Client,
+    allow_internal_targets: bool,
+}
+
+/// Redirects aren't followed, so that a receiver can't point deliveries somewhere else.
+pub fn client(config: &WebhookConfig) -> reqwest::Result<WebhookClient> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent(concat!("TempFiles/", env!("CARGO_PKG_VERSION")));
+    if !config.allow_internal_targets {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    Ok(WebhookClient {
+        http: builder.build()?,
+        allow_internal_targets: config.allow_internal_targets,
+    })
+}
+
+/// The HTTP status, or why there wasn't one.
+async fn send(
+    client: &WebhookClient,
+    url: &str,
+    secret: &str,
+    uuid: Uuid,
+    event: &str,
+    payload: String,
+) -> Result<u16, String> {
+    // The resolver doesn't see IP addresses, and the config may have changed since the webhook
+    // was created
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    if !client.allow_internal_targets && is_internal_url(&url) {
+        return Err(format!(
+            "{} is an internal address",
+            url.host_str().unwrap_or("")
+        ));
+    }
+    let response = client
+        .http
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-TempFiles-Delivery", uuid.to_string())
+        .header("X-TempFiles-Event", event)
+        .header("X-TempFiles-Signature", signature(secret, &payload))
+        .body(payload)
+        .send()
+        .await
+        // Include the cause, such as "Connection refused"
+        .map_err(|e| format!("{:#}", anyhow::Error::new(e)))?;
+    Ok(response.status().as_u16())
+}
+
+/// Send deliveries that are due, returning how many were attempted.
+///
+/// Any `2xx` response counts as delivered; anything else is retried later, until
+/// `MAX_ATTEMPTS` is reached.
+pub async fn deliver_due(
+    conn: &mut SqliteConnection,
+    client: &WebhookClient,
+) -> sqlx::Result<usize> {
+    let rows = query!(
+        r#"
+        SELECT webhook_deliveries.id, webhook_deliveries.uuid AS "uuid: Uuid", event, payload,
+        attempts, url, secret
+        FROM webhook_deliveries JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+        WHERE state = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+        ORDER BY next_attempt_at
+        LIMIT ?1
+        "#,
+        BATCH_SIZE,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let count = rows.len();
+    let results = futures::stream::iter(rows)
+        .map(|row| async move {
+            let result = send(
+                client,
+                &row.url,
+                &row.secret,
+                row.uuid,
+                &row.event,
+                row.payload.clone(),
+            )
+            .await;
+            (row, result)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (row, result) in results {
+        let attempts = row.attempts + 1;
+        let (status, error) = match result {
+            Ok(status) => (Some(status), None),
+            Err(e) => (None, Some(e)),
+        };
+        let state = if status.is_some_and(|x| (200..300).contains(&x)) {
+            "delivered"
+        } else if attempts >= MAX_ATTEMPTS {
+            "failed"
+        } else {
+            "pending"
+        };
+        let retry_delay = RETRY_DELAY * 4u64.pow(attempts as u32 - 1);
+        let modifier = format!("+{} seconds", retry_delay);
+        query!(
+            r#"
+            UPDATE webhook_deliveries
+            SET state = ?2, attempts = ?3, last_status = ?4, last_error = ?5,
+            next_attempt_at = DATETIME('now', ?6), updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?1
+            "#,
+            row.id,
+            state,
+            attempts,
+            status,
+            error,
+            modifier,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_db::test_db;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    const SECRET: &str = "sekrit";
+    /// The test listeners are on `127.0.0.1`
+    const LOCAL: WebhookConfig = WebhookConfig {
+        allow_internal_targets: true,
+    };
+
+    struct Received {
+        path: String,
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// Accept a single request, and reply to it with `status`.
+    async fn receive_one(status: u16) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let path = line.split(' ').nth(1).unwrap().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                let Some((name, value)) = line.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.insert(name.to_ascii_lowercase(), value.to_string());
+            }
+            let length = headers["content-length"].parse().unwrap();
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            Received {
+                path,
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            }
+        });
+        (url, handle)
+    }
+
+    async fn create_webhook(conn: &mut SqliteConnection, url: &str) -> i64 {
+        let user_uuid = Uuid::new_v4().to_string();
+        let user_id = query!(
+            "INSERT INTO users (username, uuid) VALUES ('test', ?1) RETURNING id",
+            user_uuid
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap()
+        .id;
+        let uuid = Uuid::new_v4();
+        query!(
+            r#"
+            INSERT INTO webhooks (user_id, uuid, url, events, secret)
+            VALUES (?1, ?2, ?3, '["upload"]', ?4)
+            RETURNING id
+            "#,
+            user_id,
+            uuid,
+            url,
+            SECRET,
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[rocket::async_test]
+    async fn delivers_signed_payload() {
+        let mut conn = test_db().await;
+        let (url, received) = receive_one(204).await;
+        let webhook_id = create_webhook(&mut conn, &url).await;
+        let delivery = ping(&mut conn, webhook_id).await.unwrap();
+
+        let client = client(&LOCAL).unwrap();
+        assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 1);
+        let received = received.await.unwrap();
+        assert_eq!(received.path, "/hook");
+        assert_eq!(
+            received.headers["x-tempfiles-delivery"],
+            delivery.to_string()
+        );
+        assert_eq!(received.headers["x-tempfiles-event"], "ping");
+
+        let signature = received.headers["x-tempfiles-signature"]
+            .strip_prefix("sha256=")
+            .unwrap();
+        let signature: Vec<u8> = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+            .collect();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(received.body.as_bytes());
+        mac.verify_slice(&signature).unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(payload["id"], delivery.to_string());
+        assert_eq!(payload["event"], "ping");
+        assert!(payload["file"].is_null());
+
+        let row = query!(
+            "SELECT state, attempts, last_status, last_error FROM webhook_deliveries WHERE uuid = ?1",
+            delivery
+        )
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(row.state, "delivered");
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.last_status, Some(204));
+        assert_eq!(row.last_error, None);
+
+        // Delivered, so not sent again
+        assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn retries_until_max_attempts() {
+        let mut conn = test_db().await;
+        let (url, received) = receive_one(500).await;
+        let webhook_id = create_webhook(&mut conn, &url).await;
+        let delivery = ping(&mut conn, webhook_id).await.unwrap();
+
+        let client = client(&LOCAL).unwrap();
+        assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 1);
+        received.await.unwrap();
+        let row = query!(
+            r#"
+            SELECT state, attempts, last_status, next_attempt_at > CURRENT_TIMESTAMP AS "later!: bool"
+            FROM webhook_deliveries WHERE uuid = ?1
+            "#,
+            delivery
+        )
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(row.state, "pending");
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.last_status, Some(500));
+        assert!(row.later);
+        // Not due yet
+        assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 0);
+
+        // The listener has gone, so the final attempt can't connect
+        let attempts = MAX_ATTEMPTS - 1;
+        query!(
+            r#"
+            UPDATE webhook_deliveries SET attempts = ?2, next_attempt_at = CURRENT_TIMESTAMP
+            WHERE uuid = ?1
+            "#,
+            delivery,
+            attempts,
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 1);
+        let row = query!(
+            "SELECT state, attempts, last_status, last_error FROM webhook_deliveries WHERE uuid = ?1",
+            delivery
+        )
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+        assert_eq!(row.state, "failed");
+        assert_eq!(row.attempts, MAX_ATTEMPTS);
+        assert_eq!(row.last_status, None);
+        assert!(row.last_error.is_some());
+    }
+
+    #[test]
+    fn internal_urls() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://localhost/",
+            "http://LOCALHOST./",
+            "http://foo.localhost/",
+        ] {
+            assert!(is_internal_url(&Url::parse(url).unwrap()), "{}", url);
+        }
+        for url in [
+            "https://example.com/hook",
+            "http://93.184.216.34/",
+            "http://[2606:2800:220:1::]/",
+        ] {
+            assert!(!is_internal_url(&Url::parse(url).unwrap()), "{}", url);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn refuses_internal_targets_by_default() {
+        let client = client(&WebhookConfig::default()).unwrap();
+        let (url, _received) = receive_one(204).await;
+        let port = Url::parse(&url).unwrap().port().unwrap();
+        // An IP address, which the resolver never sees, and a hostname, which it does
+        for url in [url, format!("http://localhost:{}/hook", port)] {
+            let mut conn = test_db().await;
+            let webhook_id = create_webhook(&mut conn, &url).await;
+            let delivery = ping(&mut conn, webhook_id).await.unwrap();
+            assert_eq!(deliver_due(&mut conn, &client).await.unwrap(), 1);
+            let row = query!(
+                "SELECT last_status, last_error FROM webhook_deliveries WHERE uuid = ?1",
+                delivery
+            )
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(row.last_status, None);
+            assert!(row.last_error.unwrap().contains("internal"), "{}", url);
+        }
+    }
+}
@@ -16,7 +16,12 @@ export interface HKDFKeys {
   serverTrustKey: CryptoKey,
 }
 
-async function encryptBinaryData(key: CryptoKey, iv: Uint8Array<ArrayBuffer>, data: Uint8Array<ArrayBuffer>): Promise<Uint8Array<ArrayBuffer>> {
+async function encryptBinaryData(
+  key: CryptoKey,
+  iv: Uint8Array<ArrayBuffer>,
+  data: Uint8Array<ArrayBuffer>,
+  additionalData?: Uint8Array<ArrayBuffer>,
+): Promise<Uint8Array<ArrayBuffer>> {
   if (DEBUG_CRYPTO_SECRETS) {
     const exportedKey = await crypto.subtle.exportKey('raw', key);
     console.log("encrypting", {
@@ -29,21 +34,90 @@ async function encryptBinaryData(key: CryptoKey, iv: Uint8Array<ArrayBuffer>, da
     {
       name: "AES-GCM",
       iv,
+      ...(additionalData ? {additionalData} : {}),
     },
     key,
     data,
   ));
 }
 
-async function encryptFileContents(params: CryptoParams, data: Uint8Array<ArrayBuffer>): Promise<Uint8Array<ArrayBuffer>> {
-  return await encryptBinaryData(params.key, params.data_iv, data);
+// See `src/file_format.rs` for the format description
+export const LEGACY_FORMAT_VERSION = 1;
+export const CHUNKED_FORMAT_VERSION = 2;
+export const METADATA_ENVELOPE_FORMAT_VERSION = 3;
+const CHUNKED_SEGMENT_FORMAT_VERSION = 2;
+const CHUNKED_SEGMENT_SIZE = 1024 * 1024;
+const CHUNKED_HEADER_SIZE = 12;
+const GCM_TAG_SIZE = 16;
+
+function chunkedHeader(segmentSize: number): Uint8Array<ArrayBuffer> {
+  const header = new Uint8Array(CHUNKED_HEADER_SIZE);
+  header.set(new TextEncoder().encode("TFCE"), 0);
+  header[4] = CHUNKED_SEGMENT_FORMAT_VERSION;
+  new DataView(header.buffer).setUint32(8, segmentSize);
+  return header;
+}
+
+function segmentNonce(dataIV: Uint8Array<ArrayBuffer>, index: number, isFinal: boolean): Uint8Array<ArrayBuffer> {
+  const nonce = new Uint8Array(12);
+  nonce.set(dataIV.subarray(0, 7), 0);
+  new DataView(nonce.buffer).setUint32(7, index);
+  nonce[11] = isFinal ? 1 : 0;
+  return nonce;
+}
+
+async function encryptFileContents(params: CryptoParams, file: Blob): Promise<Blob> {
+  const header = chunkedHeader(CHUNKED_SEGMENT_SIZE);
+  const parts: BlobPart[] = [header];
+  // Even an empty file has one (empty) segment, so there's always a final segment
+  const segmentCount = Math.max(1, Math.ceil(file.size / CHUNKED_SEGMENT_SIZE));
+  for (let i = 0; i < segmentCount; ++i) {
+    const start = i * CHUNKED_SEGMENT_SIZE;
+    const plaintext = new Uint8Array(await file.slice(start, start + CHUNKED_SEGMENT_SIZE).arrayBuffer());
+    parts.push(await encryptBinaryData(
+      params.key,
+      segmentNonce(params.data_iv, i, i === segmentCount - 1),
+      plaintext,
+      header,
+    ));
+  }
+  return new Blob(parts, {type: 'application/octet-stream'});
+}
+
+export interface FileMetadata {
+  name: string,
+  type?: string,
+  size?: number,
+  // Milliseconds since the Unix epoch
+  last_modified?: number,
+  notes?: string,
+}
+
+async function encryptMetadata(params: CryptoParams, metadata: FileMetadata): Promise<Uint8Array<ArrayBuffer>> {
+  return await encryptBinaryData(params.key, params.metadata_iv, new TextEncoder().encode(JSON.stringify(metadata)));
+}
+
+interface EncryptedMetadata {
+  metadata_iv: Uint8Array<ArrayBuffer>,
+  encrypted_metadata: Uint8Array<ArrayBuffer>,
+  format_version: number,
 }
 
-async function encryptFileName(params: CryptoParams, filename: string): Promise<Uint8Array<ArrayBuffer>> {
-  return await encryptBinaryData(params.key, params.filename_iv, new TextEncoder().encode(filename));
+export async function decryptMetadata(key: CryptoKey, file: EncryptedMetadata): Promise<FileMetadata> {
+  const decrypted = new TextDecoder().decode(await decrypt(key, file.metadata_iv, file.encrypted_metadata));
+  if (file.format_version < METADATA_ENVELOPE_FORMAT_VERSION) {
+    // Older versions only stored the filename
+    return {name: decrypted};
+  }
+  return JSON.parse(decrypted);
 }
 
-export async function decrypt(key: CryptoKey, iv: Uint8Array<ArrayBuffer>, data: Uint8Array<ArrayBuffer>): Promise<Uint8Array<ArrayBuffer>> {
+export async function decrypt(
+  key: CryptoKey,
+  iv: Uint8Array<ArrayBuffer>,
+  data: Uint8Array<ArrayBuffer>,
+  additionalData?: Uint8Array<ArrayBuffer>,
+): Promise<Uint8Array<ArrayBuffer>> {
   if (DEBUG_CRYPTO_SECRETS) {
     const exported_key = await crypto.subtle.exportKey('raw', key);
     console.log("decrypting", {
@@ -56,12 +130,116 @@ export async function decrypt(key: CryptoKey, iv: Uint8Array<ArrayBuffer>, data:
     {
       name: "AES-GCM",
       iv,
+      ...(additionalData ? {additionalData} : {}),
     },
     key,
     data,
   ));
 }
 
+function concat(a: Uint8Array<ArrayBuffer>, b: Uint8Array<ArrayBuffer>): Uint8Array<ArrayBuffer> {
+  const result = new Uint8Array(a.length + b.length);
+  result.set(a, 0);
+  result.set(b, a.length);
+  return result;
+}
+
+// Decrypts file contents as they are downloaded
+export interface Decryptor {
+  // Discard everything written so far, e.g. because the download restarted
+  reset(): void,
+  write(data: Uint8Array<ArrayBuffer>): Promise<void>,
+  finish(): Promise<Blob>,
+}
+
+class LegacyDecryptor implements Decryptor {
+  private parts: Uint8Array<ArrayBuffer>[] = [];
+
+  constructor(private key: CryptoKey, private iv: Uint8Array<ArrayBuffer>) {
+  }
+
+  reset(): void {
+    this.parts = [];
+  }
+
+  async write(data: Uint8Array<ArrayBuffer>): Promise<void> {
+    this.parts.push(data);
+  }
+
+  async finish(): Promise<Blob> {
+    const data = this.parts.reduce(concat, new Uint8Array(0));
+    return new Blob([await decrypt(this.key, this.iv, data)]);
+  }
+}
+
+class ChunkedDecryptor implements Decryptor {
+  private header: Uint8Array<ArrayBuffer> | null = null;
+  private segmentSize = 0;
+  private segmentIndex = 0;
+  private pending: Uint8Array<ArrayBuffer> = new Uint8Array(0);
+  private plaintext: Uint8Array<ArrayBuffer>[] = [];
+
+  constructor(private key: CryptoKey, private dataIV: Uint8Array<ArrayBuffer>) {
+  }
+
+  reset(): void {
+    this.header = null;
+    this.segmentIndex = 0;
+    this.pending = new Uint8Array(0);
+    this.plaintext = [];
+  }
+
+  async write(data: Uint8Array<ArrayBuffer>): Promise<void> {
+    this.pending = concat(this.pending, data);
+    if (this.header === null) {
+      if (this.pending.length < CHUNKED_HEADER_SIZE) {
+        return;
+      }
+      this.header = this.pending.slice(0, CHUNKED_HEADER_SIZE);
+      this.pending = this.pending.slice(CHUNKED_HEADER_SIZE);
+      this.segmentSize = new DataView(this.header.buffer).getUint32(8);
+    }
+
+    // A full segment might be the final one, so only decrypt it once more data follows
+    const encryptedSegmentSize = this.segmentSize + GCM_TAG_SIZE;
+    while (this.pending.length > encryptedSegmentSize) {
+      await this.decryptSegment(this.pending.slice(0, encryptedSegmentSize), false);
+      this.pending = this.pending.slice(encryptedSegmentSize);
+    }
+  }
+
+  async finish(): Promise<Blob> {
+    if (this.header === null) {
+      throw new Error("Encrypted file is truncated");
+    }
+    await this.decryptSegment(this.pending, true);
+    return new Blob(this.plaintext);
+  }
+
+  private async decryptSegment(segment: Uint8Array<ArrayBuffer>, isFinal: boolean): Promise<void> {
+    this.plaintext.push(await decrypt(
+      this.key,
+      segmentNonce(this.dataIV, this.segmentIndex, isFinal),
+      segment,
+      this.header!,
+    ));
+    ++this.segmentIndex;
+  }
+}
+
+export function createDecryptor(key: CryptoKey, dataIV: Uint8Array<ArrayBuffer>, formatVersion: number): Decryptor {
+  switch (formatVersion) {
+    case LEGACY_FORMAT_VERSION:
+      return new LegacyDecryptor(key, dataIV);
+    case CHUNKED_FORMAT_VERSION:
+    // Only the metadata changed; the data is framed in the same way
+    case METADATA_ENVELOPE_FORMAT_VERSION:
+      return new ChunkedDecryptor(key, dataIV);
+    default:
+      throw new Error(`Unsupported file format version ${formatVersion}`);
+  }
+}
+
 export async function getHKDFKeys(): Promise<HKDFKeys> {
   let [e2eeKey, serverTrustKey] = [
     await Session.deriveE2EEKey(),
@@ -100,7 +278,7 @@ export async function deriveKey(hkdf_key: CryptoKey, salt: Uint8Array<ArrayBuffe
 interface CryptoParams {
   salt: Uint8Array<ArrayBuffer>,
   key: CryptoKey,
-  filename_iv: Uint8Array<ArrayBuffer>,
+  metadata_iv: Uint8Array<ArrayBuffer>,
   data_iv: Uint8Array<ArrayBuffer>,
 }
 
@@ -110,19 +288,19 @@ async function generateParametersForNewFile(hkdf_key: CryptoKey): Promise<Crypto
   const params: CryptoParams = {
     salt,
     key,
-    filename_iv: crypto.getRandomValues(new Uint8Array(12)),
+    metadata_iv: crypto.getRandomValues(new Uint8Array(12)),
     data_iv: crypto.getRandomValues(new Uint8Array(12)),
   };
   if (DEBUG_CRYPTO_SECRETS) {
     console.log({
-      filename_iv: Base64.encode(params.filename_iv),
+      metadata_iv: Base64.encode(params.metadata_iv),
       data_iv: Base64.encode(params.data_iv),
     });
   }
   return params;
 }
 
-export type EncryptedFile = Omit<UploadFile.Request, "expires_at" | "max_downloads" | "uuid">;
+export type EncryptedFile = Omit<UploadFile.Request, "expires_at" | "max_downloads" | "target_device" | "uuid">;
 
 export async function encrypt(
   file: File, hkdfKeys: HKDFKeys): Promise<EncryptedFile> {
@@ -134,28 +312,21 @@ export async function encrypt(
   }
 
   const crypto_params = await generateParametersForNewFile(hkdfKey);
-  const encrypted_filename = await encryptFileName(crypto_params, file.name);
-  const unencrypted_data = new Uint8Array(await new Promise((resolve, reject) => {
-    const reader = new FileReader();
-    reader.onload = () => {
-      resolve(new Uint8Array(reader.result as ArrayBuffer));
-    };
-    reader.onerror = reject;
-    reader.readAsArrayBuffer(file);
-  }));
-
-  const encrypted_data = await encryptFileContents(
-    crypto_params,
-    unencrypted_data,
-  );
+  const encrypted_metadata = await encryptMetadata(crypto_params, {
+    name: file.name,
+    type: file.type || undefined,
+    size: file.size,
+    last_modified: file.lastModified,
+  });
+  const encrypted_data = await encryptFileContents(crypto_params, file);
 
   return {
     is_e2ee: isE2EE,
     salt: crypto_params.salt,
-    filename_iv: crypto_params.filename_iv,
+    metadata_iv: crypto_params.metadata_iv,
     data_iv: crypto_params.data_iv,
-    encrypted_filename,
+    encrypted_metadata,
     encrypted_data,
+    format_version: METADATA_ENVELOPE_FORMAT_VERSION,
   };
 }
-
@@ -77,6 +77,24 @@ export function getToken(): string {
   return token;
 }
 
+// Devices outlive sessions, so they're kept in localStorage rather than sessionStorage
+
+export function getDeviceUUID(): string | null {
+  return localStorage.getItem("device_uuid");
+}
+
+export function setDeviceUUID(uuid: string | null): void {
+  if (uuid === null) {
+    localStorage.removeItem("device_uuid");
+  } else {
+    localStorage.setItem("device_uuid", uuid);
+  }
+}
+
+export function defaultDeviceName(): string {
+  return navigator.platform || "Browser";
+}
+
 export function clear(): void {
   sessionStorage.clear();
 }
\ No newline at end of file
@@ -0,0 +1,14 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {UsageResponse} from "../../gen/api/account/UsageResponse";
+import * as APICall from "../APICall";
+
+export type {UsageResponse as Response}
+
+export async function exec(): Promise<UsageResponse> {
+  return await APICall.authenticatedJSON("/api/account/usage");
+}
\ No newline at end of file
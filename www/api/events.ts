@@ -0,0 +1,44 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {FileEvent} from "../gen/api/events/FileEvent";
+import * as APICall from "./APICall";
+
+export type Event = FileEvent | { type: "resync" };
+
+// EventSource can't send the Authorization header, so parse the stream ourselves
+export async function subscribe(onEvent: (event: Event) => void, signal: AbortSignal): Promise<void> {
+  const response = await APICall.authenticated("/api/events", {method: "GET", signal});
+  const reader = response.body!.pipeThrough(new TextDecoderStream()).getReader();
+  let buffer = "";
+  while (true) {
+    const {value, done} = await reader.read();
+    if (done) {
+      return;
+    }
+    buffer += value;
+    let end;
+    while ((end = buffer.indexOf("\n\n")) !== -1) {
+      const message = buffer.slice(0, end);
+      buffer = buffer.slice(end + 2);
+
+      let name = "message";
+      let data = "";
+      for (const line of message.split("\n")) {
+        if (line.startsWith("event:")) {
+          name = line.slice(6).trim();
+        } else if (line.startsWith("data:")) {
+          data += line.slice(5).trim();
+        }
+      }
+      if (name === "resync") {
+        onEvent({type: "resync"});
+      } else if (data !== "") {
+        onEvent(JSON.parse(data));
+      }
+    }
+  }
+}
@@ -6,18 +6,24 @@ export default class File {
   created_at: number;
   is_e2ee: boolean;
   salt: Uint8Array<ArrayBuffer>;
-  filename_iv: Uint8Array<ArrayBuffer>;
+  metadata_iv: Uint8Array<ArrayBuffer>;
   data_iv: Uint8Array<ArrayBuffer>;
-  encrypted_filename: Uint8Array<ArrayBuffer>;
+  encrypted_metadata: Uint8Array<ArrayBuffer>;
+  format_version: number;
+  uploaded_by_device: string | null;
+  target_device: string | null;
 
   constructor(data: WireFormat) {
     this.uuid = data.uuid;
     this.created_at = data.created_at;
     this.is_e2ee = data.is_e2ee;
     this.salt = Base64.decode(data.salt);
-    this.filename_iv = Base64.decode(data.filename_iv);
+    this.metadata_iv = Base64.decode(data.metadata_iv);
     this.data_iv = Base64.decode(data.data_iv);
-    this.encrypted_filename = Base64.decode(data.encrypted_filename);
+    this.encrypted_metadata = Base64.decode(data.encrypted_metadata);
+    this.format_version = data.format_version;
+    this.uploaded_by_device = data.uploaded_by_device;
+    this.target_device = data.target_device;
   }
 
   toJSON(): WireFormat {
@@ -26,9 +32,12 @@ export default class File {
       created_at: this.created_at,
       is_e2ee: this.is_e2ee,
       salt: Base64.encode(this.salt),
-      filename_iv: Base64.encode(this.filename_iv),
+      metadata_iv: Base64.encode(this.metadata_iv),
       data_iv: Base64.encode(this.data_iv),
-      encrypted_filename: Base64.encode(this.encrypted_filename),
+      encrypted_metadata: Base64.encode(this.encrypted_metadata),
+      format_version: this.format_version,
+      uploaded_by_device: this.uploaded_by_device,
+      target_device: this.target_device,
     };
   }
 }
\ No newline at end of file
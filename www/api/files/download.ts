@@ -5,28 +5,91 @@
  */
 
 import {DownloadRequest} from "../../gen/api/files/DownloadRequest";
+import {DownloadTicket} from "../../gen/api/files/DownloadTicket";
 import * as APICall from "../APICall";
+import * as DownloadRelease from "./download_release";
 
 export type {DownloadRequest as Request}
 
 export interface Response {
-  encrypted_contents: Uint8Array<ArrayBuffer>,
+  lease: string,
   is_final_download: boolean,
+}
+
+// Receives the encrypted contents as they arrive
+export interface Sink {
+  // The download restarted from the beginning
+  reset(): void,
+  write(data: Uint8Array<ArrayBuffer>): Promise<void>,
+}
+
+const MAX_ATTEMPTS = 5;
+
+async function fetchResumable(url: string, sink: Sink): Promise<void> {
+  let received = 0;
+  let etag: string | null = null;
+
+  for (let attempt = 1; ; ++attempt) {
+    const headers: Record<string, string> = {};
+    if (received > 0 && etag !== null) {
+      headers["Range"] = `bytes=${received}-`;
+      headers["If-Range"] = etag;
+    }
 
+    // HTTP errors won't be fixed by retrying, and nor will errors from the sink;
+    // network errors might be
+    let reader: ReadableStreamDefaultReader<Uint8Array<ArrayBuffer>>;
+    try {
+      const response = await APICall.unauthenticated(url, {headers});
+      if (response.status !== 206) {
+        // Either the first request, or the server is sending the whole file again
+        sink.reset();
+        received = 0;
+      }
+      etag = response.headers.get("ETag");
+      reader = response.body!.getReader();
+    } catch (e) {
+      if (e instanceof Response || attempt >= MAX_ATTEMPTS) {
+        throw e;
+      }
+      continue;
+    }
+
+    while (true) {
+      let chunk: ReadableStreamReadResult<Uint8Array<ArrayBuffer>>;
+      try {
+        chunk = await reader.read();
+      } catch (e) {
+        if (attempt >= MAX_ATTEMPTS) {
+          throw e;
+        }
+        break;
+      }
+      if (chunk.done) {
+        return;
+      }
+      await sink.write(chunk.value);
+      received += chunk.value.length;
+    }
+  }
 }
 
-export async function exec(request: DownloadRequest): Promise<Response> {
-  const response = await APICall.authenticated(
-    "/api/files/download",
+export async function exec(request: DownloadRequest, sink: Sink): Promise<Response> {
+  const ticket: DownloadTicket = await APICall.authenticatedJSON(
+    "/api/files/download/ticket",
     {
       body: JSON.stringify(request),
-      headers: {
-        "Accept": "application/octet-stream",
-      }
     },
   );
+  try {
+    await fetchResumable(ticket.url, sink);
+  } catch (e) {
+    // Give the download back, rather than leaving it reserved until the lease expires
+    await DownloadRelease.exec({lease: ticket.lease}).catch(() => {});
+    throw e;
+  }
   return {
-    encrypted_contents: await response.bytes() as Uint8Array<ArrayBuffer>,
-    is_final_download: response.headers.get("X-Final-Download") === "true",
+    lease: ticket.lease,
+    is_final_download: ticket.is_final_download,
   };
 }
\ No newline at end of file
@@ -0,0 +1,17 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {DownloadAckRequest} from "../../gen/api/files/DownloadAckRequest";
+import * as APICall from "../APICall";
+
+export async function exec(request: DownloadAckRequest): Promise<void> {
+  await APICall.authenticated(
+    "/api/files/download/ack",
+    {
+      body: JSON.stringify(request),
+    },
+  );
+}
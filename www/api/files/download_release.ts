@@ -0,0 +1,17 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {DownloadReleaseRequest} from "../../gen/api/files/DownloadReleaseRequest";
+import * as APICall from "../APICall";
+
+export async function exec(request: DownloadReleaseRequest): Promise<void> {
+  await APICall.authenticated(
+    "/api/files/download/release",
+    {
+      body: JSON.stringify(request),
+    },
+  );
+}
@@ -13,8 +13,13 @@ interface Response {
 }
 
 export async function exec(): Promise<Response> {
-  const body: ServerResponse = await APICall.authenticatedJSON("/api/files/list");
-  return {
-    files: body.files.map((f) => new APIFile(f)),
-  };
+  const files: APIFile[] = [];
+  let cursor: string | null = null;
+  do {
+    const uri: string = cursor === null ? "/api/files/list" : `/api/files/list?cursor=${encodeURIComponent(cursor)}`;
+    const body: ServerResponse = await APICall.authenticatedJSON(uri);
+    files.push(...body.files.map((f) => new APIFile(f)));
+    cursor = body.next_cursor;
+  } while (cursor !== null);
+  return {files};
 }
\ No newline at end of file
@@ -0,0 +1,17 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {RestoreRequest} from "../../gen/api/files/RestoreRequest";
+import * as APICall from "../APICall";
+
+export async function exec(request: RestoreRequest): Promise<void> {
+  await APICall.authenticated(
+    "/api/files/restore",
+    {
+      body: JSON.stringify(request),
+    },
+  );
+}
\ No newline at end of file
@@ -4,57 +4,116 @@
  *
  */
 
-import {UploadRequest as ClientRequest} from "../../gen/api/files/UploadRequest";
-import {UploadResponse as ServerResponse} from "../../gen/api/files/UploadResponse";
 import APIFile from "./File";
 import * as Base64 from "../../Base64";
 import * as APICall from "../APICall";
+import * as Session from "../../Session";
 
 export interface Request {
   uuid: string,
   is_e2ee: boolean,
   salt: Uint8Array<ArrayBuffer>,
-  filename_iv: Uint8Array<ArrayBuffer>,
+  metadata_iv: Uint8Array<ArrayBuffer>,
   data_iv: Uint8Array<ArrayBuffer>,
-  encrypted_filename: Uint8Array<ArrayBuffer>,
-  encrypted_data: Uint8Array<ArrayBuffer>,
+  encrypted_metadata: Uint8Array<ArrayBuffer>,
+  encrypted_data: Blob,
+  format_version: number,
   expires_at: null | Date,
   max_downloads: null | number,
+  // A device from `/api/devices/list`
+  target_device: null | string,
 }
 
 export interface Response {
   file: APIFile,
 }
 
+// Uploads use the tus resumable upload protocol; see https://tus.io/protocols/resumable-upload
+const TUS_VERSION = "1.0.0";
+const CHUNK_SIZE = 8 * 1024 * 1024;
+const MAX_ATTEMPTS = 5;
+
+function tusMetadata(metadata: Record<string, string | null>): string {
+  return Object.entries(metadata)
+    .filter(([_, value]) => value !== null)
+    .map(([key, value]) => `${key} ${btoa(value!)}`)
+    .join(",");
+}
+
+async function getOffset(location: string): Promise<number> {
+  const response = await APICall.authenticated(location, {
+    method: "HEAD",
+    headers: {"Tus-Resumable": TUS_VERSION},
+  });
+  return Number(response.headers.get("Upload-Offset"));
+}
+
 export async function exec(req: Request): Promise<Response> {
-  const clientRequest: ClientRequest = {
+  const data = req.encrypted_data;
+  const wire = {
     uuid: req.uuid,
-    is_e2ee: req.is_e2ee ? 'true' : 'false', // needed for JS <-> Rust FormData, as opposed to JSON
+    is_e2ee: req.is_e2ee,
     salt: Base64.encode(req.salt),
-    filename_iv: Base64.encode(req.filename_iv),
+    metadata_iv: Base64.encode(req.metadata_iv),
     data_iv: Base64.encode(req.data_iv),
-    encrypted_filename: Base64.encode(req.encrypted_filename),
-    encrypted_data: new Blob([req.encrypted_data], {type: 'application/octet-stream'}),
-    expires_at: (req.expires_at === null) ? null : req.expires_at.getTime() / 1000,
-    max_downloads: req.max_downloads,
+    encrypted_metadata: Base64.encode(req.encrypted_metadata),
+    format_version: req.format_version,
   };
-  const formRequest = new FormData();
-  for (const [key, value] of Object.entries(clientRequest)) {
-    if (value === null) {
-      continue;
-    }
+  const created = await APICall.authenticated(
+    "/api/uploads",
+    {
+      headers: {
+        "Tus-Resumable": TUS_VERSION,
+        "Upload-Length": data.size.toString(),
+        "Upload-Metadata": tusMetadata({
+          ...wire,
+          is_e2ee: wire.is_e2ee ? "true" : "false",
+          format_version: wire.format_version.toString(),
+          expires_at: (req.expires_at === null) ? null : Math.floor(req.expires_at.getTime() / 1000).toString(),
+          max_downloads: req.max_downloads?.toString() ?? null,
+          target_device: req.target_device,
+        }),
+      },
+    });
+  const location = created.headers.get("Location")!;
 
-    if (value instanceof Blob) {
-      formRequest.set(key, value);
-    } else {
-      formRequest.set(key, value.toString());
+  let offset = 0;
+  let failures = 0;
+  let offsetIsStale = false;
+  while (offset < data.size) {
+    try {
+      if (offsetIsStale) {
+        // The server may have stored some of the failed request
+        offset = await getOffset(location);
+        offsetIsStale = false;
+        continue;
+      }
+      const response = await APICall.authenticated(location, {
+        method: "PATCH",
+        headers: {
+          "Tus-Resumable": TUS_VERSION,
+          "Upload-Offset": offset.toString(),
+          "Content-Type": "application/offset+octet-stream",
+        },
+        body: data.slice(offset, offset + CHUNK_SIZE),
+      });
+      offset = Number(response.headers.get("Upload-Offset"));
+    } catch (e) {
+      // Network errors are worth retrying; HTTP errors aren't
+      if (e instanceof Response || ++failures >= MAX_ATTEMPTS) {
+        throw e;
+      }
+      offsetIsStale = true;
+      await new Promise((resolve) => setTimeout(resolve, 1000 * failures));
     }
   }
 
-  const body: ServerResponse = await APICall.authenticatedJSON(
-    "/api/files/upload",
-    {
-      body: formRequest,
-    });
-  return {file: new APIFile(body.file)};
-}
\ No newline at end of file
+  return {
+    file: new APIFile({
+      ...wire,
+      created_at: Math.floor(Date.now() / 1000),
+      uploaded_by_device: Session.getDeviceUUID(),
+      target_device: req.target_device,
+    }),
+  };
+}
@@ -0,0 +1,14 @@
+/*
+ * Copyright 2025 Fred Emmott <fred@fredemmott.com>
+ * SPDX-License-Identifier: MIT
+ *
+ */
+
+import {ServerInfoResponse} from "../gen/api/ServerInfoResponse";
+import * as APICall from "./APICall";
+
+export type {ServerInfoResponse as Response}
+
+export async function exec(): Promise<ServerInfoResponse> {
+  return await APICall.authenticatedJSON("/api/server_info");
+}
\ No newline at end of file
@@ -12,7 +12,7 @@ import * as FileCrypto from "../FileCrypto";
 interface FilesListProps {
   files: APIFile[],
   hkdfKeys: FileCrypto.HKDFKeys | null,
-  onDelete: (uuid: string) => void,
+  onDelete: (uuid: string, restorable: boolean) => void,
 }
 
 export default function FilesList({files, hkdfKeys, onDelete}: FilesListProps): ReactNode {
@@ -8,16 +8,28 @@ import APIFile from "../api/files/File";
 import React, {ReactNode, useEffect, useState} from "react";
 import * as DeleteFile from "../api/files/delete";
 import * as DownloadFile from "../api/files/download";
+import * as DownloadAck from "../api/files/download_ack";
+import * as DownloadRelease from "../api/files/download_release";
 import * as FileCrypto from "../FileCrypto";
 import * as Session from "../Session"
 
-async function downloadFile(apiFile: APIFile, key: CryptoKey, filename: string): Promise<"download-complete" | "final-download-complete"> {
-  const response = await DownloadFile.exec({uuid: apiFile.uuid});
-  const decrypted = await FileCrypto.decrypt(key, apiFile.data_iv, response.encrypted_contents);
-  const url = URL.createObjectURL(new Blob([decrypted]));
+async function downloadFile(apiFile: APIFile, key: CryptoKey, metadata: FileCrypto.FileMetadata): Promise<"download-complete" | "final-download-complete"> {
+  const decryptor = FileCrypto.createDecryptor(key, apiFile.data_iv, apiFile.format_version);
+  const response = await DownloadFile.exec({uuid: apiFile.uuid}, decryptor);
+  let decrypted: Blob;
+  try {
+    decrypted = new Blob([await decryptor.finish()], {type: metadata.type ?? ""});
+  } catch (e) {
+    // The file can't be used, so don't consume a download
+    await DownloadRelease.exec({lease: response.lease}).catch(() => {});
+    throw e;
+  }
+  // Only consume the download once we know the client can use it
+  await DownloadAck.exec({lease: response.lease});
+  const url = URL.createObjectURL(decrypted);
   const link = document.createElement('a');
   link.href = url;
-  link.download = filename;
+  link.download = metadata.name;
   link.click();
   URL.revokeObjectURL(url);
   return response.is_final_download ? "final-download-complete" : "download-complete";
@@ -36,14 +48,15 @@ async function deleteFile(uuid: string, name: string): Promise<"deleted" | "canc
 interface FileListEntryProps {
   file: APIFile,
   hkdfKeys: FileCrypto.HKDFKeys,
-  onDelete: (uuid: string) => void,
+  // `restorable` is true if the file was deleted, rather than downloaded for the last time
+  onDelete: (uuid: string, restorable: boolean) => void,
 }
 
 export default function FilesListRow({file, hkdfKeys, onDelete}: FileListEntryProps): ReactNode {
   type State = "loading" | "loaded" | "no_key" | "requires_e2ee";
   const [state, setState] = useState<State>("loading");
   const [key, setKey] = useState<CryptoKey | null>(null);
-  const [decryptedFilename, setDecryptedFilename] = useState<string | null>(null);
+  const [metadata, setMetadata] = useState<FileCrypto.FileMetadata | null>(null);
 
   useEffect(() => {
     const load = async () => {
@@ -63,8 +76,7 @@ export default function FilesListRow({file, hkdfKeys, onDelete}: FileListEntryPr
         setState("no_key");
         return;
       }
-      let decryptedFilename = await FileCrypto.decrypt(fileKey, file.filename_iv, file.encrypted_filename);
-      setDecryptedFilename(new TextDecoder().decode(decryptedFilename));
+      setMetadata(await FileCrypto.decryptMetadata(fileKey, file));
     };
     load().then(() => setState("loaded"));
   }, []);
@@ -123,11 +135,11 @@ export default function FilesListRow({file, hkdfKeys, onDelete}: FileListEntryPr
             downloadFile(
               file,
               key!,
-              decryptedFilename!,
+              metadata!,
             )
               .then((result) => {
                 if (result === 'final-download-complete') {
-                  onDelete(file.uuid);
+                  onDelete(file.uuid, false);
                 }
               })
               .catch((ex) => {
@@ -137,16 +149,16 @@ export default function FilesListRow({file, hkdfKeys, onDelete}: FileListEntryPr
                   alert(`An error occurred downloading a file: ${ex}`);
                 }
               });
-          }}>{decryptedFilename}</a>
+          }}>{metadata!.name}</a>
         </td>
         <td><span
           className={"clickable-icon"}
           onClick={
             () =>
-              deleteFile(file.uuid, decryptedFilename!)
+              deleteFile(file.uuid, metadata!.name)
                 .then((result) => {
                   if (result == "deleted") {
-                    onDelete(file.uuid);
+                    onDelete(file.uuid, true);
                   }
                 })}
           title={"Delete this file"}>🗑️</span></td>
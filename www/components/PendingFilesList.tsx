@@ -8,18 +8,20 @@ import React, {ReactNode} from 'react';
 import PendingFile from "../PendingFile";
 import PendingFilesListRow from "./PendingFilesListRow";
 import APIFile from "../api/files/File";
+import * as ServerInfo from "../api/server_info";
 
 interface Props {
   files: PendingFile[],
+  serverInfo: ServerInfo.Response,
   onUpload: (file: APIFile) => void,
 }
 
-export default function PendingFilesList({files, onUpload}: Props): ReactNode {
+export default function PendingFilesList({files, serverInfo, onUpload}: Props): ReactNode {
   if (files.length === 0) {
     return null;
   }
   return <div className={"new-files-section"}>
     <h2>⚠️ Unsaved files</h2>
-    {files.map((f) => <PendingFilesListRow key={f.uuid} file={f} onUpload={onUpload}/>)}
+    {files.map((f) => <PendingFilesListRow key={f.uuid} file={f} serverInfo={serverInfo} onUpload={onUpload}/>)}
   </div>;
 }
\ No newline at end of file
@@ -8,21 +8,58 @@ import React, {Fragment, ReactNode, useId, useState} from 'react';
 import PendingFile from "../PendingFile";
 import APIFile from "../api/files/File";
 import * as UploadFile from "../api/files/upload";
+import * as ServerInfo from "../api/server_info";
+import {InsufficientStorage} from "../gen/api/InsufficientStorage";
+import {QuotaExceeded} from "../gen/api/QuotaExceeded";
 
 interface Props {
   file: PendingFile,
+  serverInfo: ServerInfo.Response,
   onUpload: (_: APIFile) => void,
 }
 
+interface Lifetime {
+  label: string,
+  // null for unlimited
+  seconds: number | null,
+}
+
+const LIFETIMES: Lifetime[] = [
+  {label: "1 hour", seconds: 60 * 60},
+  {label: "24 hours", seconds: 24 * 60 * 60},
+  {label: "1 week", seconds: 7 * 24 * 60 * 60},
+  {label: "unlimited", seconds: null},
+];
+
+// The choices permitted by the server's retention policy
+function getLifetimes({max_expires_in}: ServerInfo.Response): Lifetime[] {
+  if (max_expires_in === null) {
+    return LIFETIMES;
+  }
+  const allowed = LIFETIMES.filter(({seconds}) => seconds !== null && seconds <= max_expires_in);
+  if (!allowed.some(({seconds}) => seconds === max_expires_in)) {
+    allowed.push({label: "maximum", seconds: max_expires_in});
+  }
+  return allowed;
+}
+
+function getDefaultLifetime(lifetimes: Lifetime[], {default_expires_in}: ServerInfo.Response): Lifetime {
+  if (default_expires_in === null) {
+    return lifetimes.find(({label}) => label === "24 hours") ?? lifetimes[lifetimes.length - 1];
+  }
+  return lifetimes.find(({seconds}) => seconds !== null && seconds >= default_expires_in)
+    ?? lifetimes[lifetimes.length - 1];
+}
+
 interface UploadState {
   clicked: boolean,
   progress: "not-started" | "in-progress" | "completed",
 }
 
-export default function PendingFilesListRow({file, onUpload}: Props): ReactNode {
-  type Expiration = "1 hour" | "24 hours" | "1 week" | "never";
-  const [expiration, setExpiration] = useState<Expiration>("24 hours");
-  const [singleDownload, setSingleDownload] = useState<boolean>(false);
+export default function PendingFilesListRow({file, serverInfo, onUpload}: Props): ReactNode {
+  const lifetimes = getLifetimes(serverInfo);
+  const [expiration, setExpiration] = useState<Lifetime>(() => getDefaultLifetime(lifetimes, serverInfo));
+  const [singleDownload, setSingleDownload] = useState<boolean>(serverInfo.require_max_downloads);
   const [uploadState, setUploadState] = useState<UploadState>({clicked: false, progress: "not-started"});
   const singleDownloadId = useId();
   const lifetimeId = useId();
@@ -32,11 +69,25 @@ export default function PendingFilesListRow({file, onUpload}: Props): ReactNode
     UploadFile.exec({
       ...file.encryptedFile!,
       uuid: file.uuid,
-      expires_at: null,
+      expires_at: (expiration.seconds === null) ? null : new Date(Date.now() + expiration.seconds * 1000),
       max_downloads: singleDownload ? 1 : null,
+      target_device: null,
     }).then(({file}) => {
       setUploadState((prev) => ({...prev, progress: "completed"}));
       onUpload(file);
+    }).catch(async (e) => {
+      // 413: larger than the quota; 507: the quota or the server's disk is full
+      if (e instanceof Response && (e.status === 413 || e.status === 507)) {
+        const body: QuotaExceeded | InsufficientStorage | null = await e.json().catch(() => null);
+        if (body?.code === "insufficient_storage") {
+          alert(`The server is low on disk space, so ${file.fileName} can't be uploaded right now; try again later.`);
+        } else {
+          alert(`There is not enough space left to upload ${file.fileName}; delete some files, then try again.`);
+        }
+        setUploadState({clicked: false, progress: "not-started"});
+        return;
+      }
+      throw e;
     });
   }
 
@@ -52,24 +103,25 @@ export default function PendingFilesListRow({file, onUpload}: Props): ReactNode
         type={"checkbox"}
         name={"singleDownload"}
         checked={singleDownload}
+        disabled={committed || serverInfo.require_max_downloads}
         onChange={() => setSingleDownload(!singleDownload)}
       />
       <label htmlFor={singleDownloadId}>Delete after single download</label>
       <legend>Expiration:</legend>
       <fieldset id={lifetimeId} disabled={committed}>
         {
-          ["1 hour", "24 hours", "1 week", "unlimited"].map((lifetime) => {
-            const id = `${lifetimeId}-${lifetime}`;
+          lifetimes.map((lifetime) => {
+            const id = `${lifetimeId}-${lifetime.label}`;
             return (<Fragment key={id}>
               <input
                 id={id}
                 type={"radio"}
                 name={"keepFor"}
-                value={lifetime}
-                checked={expiration === lifetime}
-                onChange={() => setExpiration(lifetime as Expiration)}
+                value={lifetime.label}
+                checked={expiration.label === lifetime.label}
+                onChange={() => setExpiration(lifetime)}
               />
-              <label htmlFor={id}>{lifetime}</label>
+              <label htmlFor={id}>{lifetime.label}</label>
             </Fragment>);
           })
         }
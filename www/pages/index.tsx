@@ -8,6 +8,10 @@ import React, {ReactNode, useEffect, useState} from 'react'
 import * as Session from '../Session'
 import * as ListFiles from '../api/files/list'
 import * as DeleteAllFiles from '../api/files/delete_all'
+import * as RestoreFile from '../api/files/restore'
+import * as GetUsage from '../api/account/usage'
+import * as ServerInfo from '../api/server_info'
+import * as Events from '../api/events'
 import PendingFilesList from "../components/PendingFilesList"
 import APIFile from '../api/files/File'
 import {Navigate, useNavigate} from "react-router";
@@ -39,6 +43,29 @@ function E2EEWarning(): ReactNode {
   </div>
 }
 
+function formatBytes(bytes: number): string {
+  const units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    ++unit;
+  }
+  return unit === 0 ? `${bytes} ${units[unit]}` : `${bytes.toFixed(1)} ${units[unit]}`;
+}
+
+function StorageUsage({usage}: { usage: GetUsage.Response | null }): ReactNode {
+  if (usage === null || (usage.max_bytes === null && usage.max_files === null)) {
+    return null;
+  }
+  const limits: string[] = [];
+  if (usage.max_bytes !== null) {
+    limits.push(`${formatBytes(usage.used_bytes)} of ${formatBytes(usage.max_bytes)}`);
+  }
+  if (usage.max_files !== null) {
+    limits.push(`${usage.used_files} of ${usage.max_files} files`);
+  }
+  return <div>{"💽 "}Using {limits.join(", ")}</div>
+}
 
 export default function IndexPage(): ReactNode {
   if (!Session.isLoggedIn()) {
@@ -47,14 +74,37 @@ export default function IndexPage(): ReactNode {
   const [files, setFiles] = useState<APIFile[]>([]);
   const [pendingFiles, setPendingFiles] = useState<PendingFile[]>([]);
   const [hkdfKeys, setHKDFKeys] = useState<HKDFKeys | null>(null);
+  const [usage, setUsage] = useState<GetUsage.Response | null>(null);
+  const [serverInfo, setServerInfo] = useState<ServerInfo.Response | null>(null);
+  // Files that can be restored from the trash with 'undo'
+  const [recentlyDeleted, setRecentlyDeleted] = useState<APIFile[]>([]);
   const navigate = useNavigate();
 
   useEffect(() => {
     FileCrypto.getHKDFKeys().then(setHKDFKeys);
     ListFiles.exec().then((response) => setFiles(response.files));
+    ServerInfo.exec().then(setServerInfo);
+  }, []);
+
+  useEffect(() => {
+    // Pick up changes made from other devices
+    const controller = new AbortController();
+    Events.subscribe((event) => {
+      if (event.type === "file-deleted" || event.type === "file-expired") {
+        setFiles((prev) => prev.filter((file) => file.uuid !== event.uuid));
+        return;
+      }
+      ListFiles.exec().then((response) => setFiles(response.files));
+    }, controller.signal).catch(() => {
+    });
+    return () => controller.abort();
   }, []);
 
-  if (!hkdfKeys) {
+  useEffect(() => {
+    GetUsage.exec().then(setUsage);
+  }, [files]);
+
+  if (!hkdfKeys || !serverInfo) {
     return <div className={"index-page index-page-loading"}>
       <div className={"index-page-loading-icon"}>⏳</div>
       <div className={"index-page-loading-text"}>Loading...</div>
@@ -64,6 +114,7 @@ export default function IndexPage(): ReactNode {
   return <div className={"index-page"}>
     <div className={"header"}>
       <E2EEWarning/>
+      <StorageUsage usage={usage}/>
       <div>
         {"🗑️ "}
         <a href="#" onClick={(e) => {
@@ -75,10 +126,30 @@ export default function IndexPage(): ReactNode {
           }
 
           DeleteAllFiles.exec().then(() => {
+            if (serverInfo.trash_period !== null) {
+              setRecentlyDeleted(files);
+            }
             setFiles([]);
           });
         }}>Delete all files</a>
       </div>
+      {recentlyDeleted.length > 0 && <div>
+        {"↩️ "}
+        <a href="#" onClick={(e) => {
+          e.preventDefault();
+          e.stopPropagation();
+
+          const restoring = recentlyDeleted;
+          setRecentlyDeleted([]);
+          Promise.allSettled(restoring.map((file) => RestoreFile.exec({uuid: file.uuid}))).then((results) => {
+            const restored = restoring.filter((_, i) => results[i].status === "fulfilled");
+            setFiles((prev) => [...restored, ...prev]);
+            if (restored.length < restoring.length) {
+              alert(`${restoring.length - restored.length} files could not be restored.`);
+            }
+          });
+        }}>Undo delete</a>
+      </div>}
       <div>
         {"🔌 "}
         <a href="#" onClick={(e) => {
@@ -121,6 +192,7 @@ export default function IndexPage(): ReactNode {
     />
     <PendingFilesList
       files={pendingFiles}
+      serverInfo={serverInfo}
       onUpload={(file) => {
         setFiles((prev) => [file, ...prev]);
         setPendingFiles((prev) => prev.filter((it) => it.uuid !== file.uuid));
@@ -129,9 +201,12 @@ export default function IndexPage(): ReactNode {
     <FilesList
       files={files}
       hkdfKeys={hkdfKeys}
-      onDelete={(uuid) =>
-        setFiles(files.filter((file) => file.uuid !== uuid))
-      }
+      onDelete={(uuid, restorable) => {
+        if (restorable && serverInfo.trash_period !== null) {
+          setRecentlyDeleted(files.filter((file) => file.uuid === uuid));
+        }
+        setFiles(files.filter((file) => file.uuid !== uuid));
+      }}
     />
     <div className={"footer"}>
       Powered by {' '}
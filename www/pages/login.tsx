@@ -123,6 +123,8 @@ async function login(setState: (state: States.Any) => void): Promise<void> {
     result = await FinishLogin.exec({
       challenge_uuid: challenge.challenge_uuid,
       credential,
+      device_uuid: Session.getDeviceUUID(),
+      device_name: Session.getDeviceUUID() === null ? Session.defaultDeviceName() : null,
     });
   } catch (ex) {
     if (ex instanceof Response) {
@@ -132,6 +134,7 @@ async function login(setState: (state: States.Any) => void): Promise<void> {
     throw ex;
   }
 
+  Session.setDeviceUUID(result.device);
   Session.initialize({
     session: result.session,
     username: result.username,